@@ -17,7 +17,15 @@
 
 use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 
-use crate::waveform::Waveform;
+use crate::{
+    filter::{FilterMode, FilterType, Slope},
+    lfo::{Division, LfoMode, LfoShape},
+    modulation::{ModDestination, ModSource},
+    oscilator::{FmMode, OscRef, Quality},
+    velocity::VelocityCurve,
+    voice::StealPolicy,
+    waveform::Waveform,
+};
 
 pub struct AtomicF32 {
     inner: AtomicU32,
@@ -41,24 +49,45 @@ impl AtomicF32 {
     }
 }
 
-pub struct AtomicWaveform {
-    inner: AtomicI32,
-}
-
-impl AtomicWaveform {
-    pub fn new(v: Waveform) -> Self {
-        Self {
-            inner: AtomicI32::new(v as i32),
+/// Declares an atomic wrapper around a fieldless enum that converts to and
+/// from `i32`.
+macro_rules! atomic_enum {
+    ($name:ident, $ty:ty) => {
+        pub struct $name {
+            inner: AtomicI32,
         }
-    }
 
-    #[inline(always)]
-    pub fn load(&self, order: Ordering) -> Waveform {
-        Waveform::from(self.inner.load(order))
-    }
+        impl $name {
+            pub fn new(v: $ty) -> Self {
+                Self {
+                    inner: AtomicI32::new(v as i32),
+                }
+            }
 
-    #[inline(always)]
-    pub fn store(&self, val: Waveform, order: Ordering) {
-        self.inner.store(val as i32, order)
-    }
+            #[inline(always)]
+            pub fn load(&self, order: Ordering) -> $ty {
+                <$ty>::from(self.inner.load(order))
+            }
+
+            #[inline(always)]
+            pub fn store(&self, val: $ty, order: Ordering) {
+                self.inner.store(val as i32, order)
+            }
+        }
+    };
 }
+
+atomic_enum!(AtomicWaveform, Waveform);
+atomic_enum!(AtomicQuality, Quality);
+atomic_enum!(AtomicFmMode, FmMode);
+atomic_enum!(AtomicOscRef, OscRef);
+atomic_enum!(AtomicFilterMode, FilterMode);
+atomic_enum!(AtomicFilterType, FilterType);
+atomic_enum!(AtomicSlope, Slope);
+atomic_enum!(AtomicLfoShape, LfoShape);
+atomic_enum!(AtomicLfoMode, LfoMode);
+atomic_enum!(AtomicDivision, Division);
+atomic_enum!(AtomicStealPolicy, StealPolicy);
+atomic_enum!(AtomicVelocityCurve, VelocityCurve);
+atomic_enum!(AtomicModSource, ModSource);
+atomic_enum!(AtomicModDestination, ModDestination);
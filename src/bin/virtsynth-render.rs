@@ -0,0 +1,153 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Renders a score to a WAV file without an audio device.
+
+use std::{fs, io::BufWriter, process::ExitCode};
+
+use virtsynth::{params::Params, render, wav};
+
+const USAGE: &str = "\
+Usage: virtsynth-render <score> <output.wav> [options]
+
+Options:
+    --sample-rate <hz>   Sample rate, at least 8000 (default 48000)
+    --channels <n>       Number of channels (default 2)
+    --format <format>    int16, int24 or float32 (default int16)
+    --tail <seconds>     Time rendered after the last event (default 1.0)
+    --seed <n>           Seed for noise (default 0)";
+
+/// Lowest sample rate accepted. Below it the filter and the band-limited
+/// oscillators have almost no room under the Nyquist frequency.
+const MIN_SAMPLE_RATE: u32 = 8000;
+
+struct Args {
+    score: String,
+    output: String,
+    sample_rate: u32,
+    channels: u16,
+    format: wav::SampleFormat,
+    tail: f64,
+    seed: u64,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut positional = Vec::new();
+    let mut sample_rate = 48000;
+    let mut channels = 2;
+    let mut format = wav::SampleFormat::Int16;
+    let mut tail = 1.0;
+    let mut seed = 0;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {arg}"))?;
+        let invalid = || format!("invalid value \"{value}\" for {arg}");
+        match arg.as_str() {
+            "--sample-rate" => {
+                sample_rate = value
+                    .parse()
+                    .ok()
+                    .filter(|&rate| rate >= MIN_SAMPLE_RATE)
+                    .ok_or_else(invalid)?
+            }
+            "--channels" => channels = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?,
+            "--format" => {
+                format = match value.as_str() {
+                    "int16" => wav::SampleFormat::Int16,
+                    "int24" => wav::SampleFormat::Int24,
+                    "float32" => wav::SampleFormat::Float32,
+                    _ => return Err(invalid()),
+                }
+            }
+            "--tail" => {
+                tail = value
+                    .parse()
+                    .ok()
+                    .filter(|&tail: &f64| tail >= 0.0)
+                    .ok_or_else(invalid)?
+            }
+            "--seed" => seed = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+
+    let [score, output]: [String; 2] = positional
+        .try_into()
+        .map_err(|_| "expected a score and an output file".to_string())?;
+
+    Ok(Args {
+        score,
+        output,
+        sample_rate,
+        channels,
+        format,
+        tail,
+        seed,
+    })
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let text = fs::read_to_string(&args.score)
+        .map_err(|err| format!("failed to read {}: {err}", args.score))?;
+    let score = render::parse_score(&text).map_err(|err| format!("{}: {err}", args.score))?;
+
+    let samples = render::render(
+        Params::default(),
+        &score,
+        args.sample_rate,
+        args.channels,
+        args.tail,
+        args.seed,
+    );
+
+    let file = fs::File::create(&args.output)
+        .map_err(|err| format!("failed to create {}: {err}", args.output))?;
+    wav::write(
+        BufWriter::new(file),
+        &samples,
+        args.channels,
+        args.sample_rate,
+        args.format,
+    )
+    .map_err(|err| format!("failed to write {}: {err}", args.output))
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
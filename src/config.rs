@@ -0,0 +1,98 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Settings persisted between runs, stored as `key = value` lines in
+//! `$XDG_CONFIG_HOME/virtsynth/config`.
+
+use std::{fs, io, path::PathBuf};
+
+use crate::synthesizer::StreamSettings;
+
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct Config {
+    /// Name of the selected audio host, `None` for automatic selection.
+    pub host: Option<String>,
+    /// Name of the selected output device, `None` for the host's default.
+    pub device: Option<String>,
+    pub stream: StreamSettings,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(dir.join("virtsynth").join("config"))
+    }
+
+    /// Loads the saved config. A missing or unreadable file gives the
+    /// defaults.
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| Self::parse(&text))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Self::path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.serialize())
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "host" => config.host = Some(value.to_string()),
+                "device" => config.device = Some(value.to_string()),
+                "sample_rate" => config.stream.sample_rate = value.parse().ok(),
+                "buffer_size" => config.stream.buffer_size = value.parse().ok(),
+                "channels" => config.stream.channels = value.parse().ok(),
+                _ => {}
+            }
+        }
+        config
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = String::new();
+        if let Some(host) = &self.host {
+            text += &format!("host = {host}\n");
+        }
+        if let Some(device) = &self.device {
+            text += &format!("device = {device}\n");
+        }
+        if let Some(sample_rate) = self.stream.sample_rate {
+            text += &format!("sample_rate = {sample_rate}\n");
+        }
+        if let Some(buffer_size) = self.stream.buffer_size {
+            text += &format!("buffer_size = {buffer_size}\n");
+        }
+        if let Some(channels) = self.stream.channels {
+            text += &format!("channels = {channels}\n");
+        }
+        text
+    }
+}
@@ -0,0 +1,579 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The synthesis engine, independent of any audio or MIDI backend.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{
+    atomicf::AtomicF32,
+    envelope::{ADSR, AMP_ENVELOPE, ENVELOPES, FILTER_ENVELOPE},
+    event::{self, cc, Controllers, Event, EventSender, SharedQueueSlot, TimedEvent},
+    filter::{Filter, FilterState},
+    lfo::{Lfo, LfoMode, LfoState, LFOS},
+    modulation::{ModDestination, ModSource, Modulation, Sources},
+    mseg::Mseg,
+    noise::{Noise, Rng},
+    oscilator::{FmMatrix, Inputs, OscRef, Oscilator},
+    params::Params,
+    ringbuf::{self, Consumer},
+    velocity::Velocity,
+    voice::VoicePool,
+};
+
+/// Renders audio for [`Params`] from queued [`Event`]s. Driven by an audio
+/// callback, or directly through [`Engine::process`].
+pub struct Engine {
+    sample_rate: f32,
+    voices: VoicePool,
+    /// Indexed like [`Voice::envelopes`](crate::voice::Voice::envelopes).
+    envelopes: [ADSR; ENVELOPES],
+    velocity: Velocity,
+    /// One queue per event source, merged in time order.
+    events: Vec<Consumer<TimedEvent>>,
+    /// Replacements for the queue at each index, see [`Engine::share_queue`].
+    shared_queues: Vec<(usize, SharedQueueSlot)>,
+    controllers: Controllers,
+    pitch_bend_range: f32,
+    pitch_bend_range_a: Arc<AtomicF32>,
+    /// Frequency ratio for the current pitch bend.
+    bend: f32,
+    /// Sample frame at the start of the current buffer.
+    clock: u64,
+    clock_a: Arc<AtomicU64>,
+    gain_a: Arc<AtomicF32>,
+    oscs: [Oscilator; 3],
+    modulation: Modulation,
+    fm: FmMatrix,
+    filter: Filter,
+    mseg: Mseg,
+    lfos: [Lfo; LFOS],
+    /// Free-running states of the global LFOs.
+    global_lfos: [LfoState; LFOS],
+    tempo_a: Arc<AtomicF32>,
+    /// Seeds the noise of each new note.
+    rng: Rng,
+}
+
+impl Engine {
+    /// Creates an engine reading from `events`. `clock_a` is advanced by the
+    /// engine and timestamps the events, see [`event::queue`].
+    pub fn new(
+        sample_rate: f32,
+        params: Params,
+        events: Vec<Consumer<TimedEvent>>,
+        clock_a: Arc<AtomicU64>,
+    ) -> Self {
+        let mut rng = Rng::default();
+        Self {
+            sample_rate,
+            voices: VoicePool::new(params.polyphony, params.steal_policy),
+            envelopes: [
+                ADSR::new(params.envelope),
+                ADSR::new(params.filter.envelope.clone()),
+            ],
+            velocity: Velocity::new(params.velocity_curve, params.velocity_amount),
+            events,
+            shared_queues: Vec::new(),
+            controllers: Controllers::default(),
+            pitch_bend_range: 2.0,
+            pitch_bend_range_a: params.pitch_bend_range,
+            bend: 1.0,
+            clock: clock_a.load(Ordering::Acquire),
+            clock_a,
+            gain_a: params.gain,
+            oscs: [
+                Oscilator::new(params.osc1),
+                Oscilator::new(params.osc2),
+                Oscilator::new(params.osc3),
+            ],
+            modulation: Modulation::new(params.modulation),
+            fm: FmMatrix::new(params.fm),
+            filter: Filter::new(params.filter),
+            mseg: Mseg::new(params.mseg),
+            lfos: params.lfos.map(Lfo::new),
+            global_lfos: global_lfos(&mut rng),
+            tempo_a: params.tempo,
+            rng,
+        }
+    }
+
+    /// Creates an engine with its own clock and a single event queue.
+    pub fn with_queue(sample_rate: f32, params: Params) -> (Self, EventSender) {
+        let clock = Arc::new(AtomicU64::new(0));
+        let (sender, consumer) = event::queue(Arc::clone(&clock));
+        (
+            Self::new(sample_rate, params, vec![consumer], clock),
+            sender,
+        )
+    }
+
+    /// Adds an event source whose queue can be replaced while the engine
+    /// runs, by setting a new one in `slot`. Nothing is read from it until
+    /// the first queue is set.
+    pub fn share_queue(&mut self, slot: SharedQueueSlot) {
+        let (_, empty) = ringbuf::channel(1);
+        self.shared_queues.push((self.events.len(), slot));
+        self.events.push(empty);
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::NoteOn { key, velocity } => {
+                let mut levels = [0.0; ENVELOPES];
+                levels[AMP_ENVELOPE] = self.velocity.amplitude(velocity);
+                levels[FILTER_ENVELOPE] = self.filter.envelope_level(velocity);
+                let (voice, restarted) = self.voices.note_on(key, velocity, levels);
+                if restarted {
+                    voice.filter = FilterState::default();
+                }
+                self.mseg.press(&mut voice.mseg);
+                for state in voice.lfos.iter_mut() {
+                    *state = LfoState::new(self.rng.next_u64());
+                }
+                for (state, osc) in voice.oscs.iter_mut().zip(self.oscs.iter()) {
+                    state.noise = Noise::new(self.rng.next_u64());
+                    if restarted {
+                        state.output = [0.0; 2];
+                        for phase in state.phases.iter_mut() {
+                            *phase = osc.random_phase * (0.5 + 0.5 * self.rng.next_f32());
+                        }
+                    }
+                }
+            }
+            Event::NoteOff { key } => self.voices.note_off(key),
+            Event::Controller {
+                number: cc::ALL_NOTES_OFF,
+                ..
+            } => self.voices.release_all(),
+            Event::Controller {
+                number: cc::ALL_SOUND_OFF,
+                ..
+            } => self.voices.kill_all(),
+            Event::Controller {
+                number: cc::MOD_WHEEL,
+                value,
+            } => self.controllers.mod_wheel = value as f32 / 127.0,
+            Event::Controller { .. } => {}
+            Event::PitchBend { value } => {
+                self.controllers.pitch_bend = value;
+                self.update_bend();
+            }
+            Event::ChannelPressure { value } => self.controllers.pressure = value,
+            Event::KeyPressure { key, value } => self.voices.set_pressure(key, value),
+        }
+    }
+
+    #[inline(always)]
+    fn update_bend(&mut self) {
+        self.bend = 2.0f32.powf(self.controllers.pitch_bend * self.pitch_bend_range / 12.0);
+    }
+
+    /// Removes the earliest event due at or before `now` from any queue.
+    #[inline(always)]
+    fn next_event(&mut self, now: u64) -> Option<Event> {
+        let mut earliest: Option<(usize, u64)> = None;
+        for (index, queue) in self.events.iter().enumerate() {
+            let Some(event) = queue.peek() else {
+                continue;
+            };
+            if event.time <= now && earliest.is_none_or(|(_, time)| event.time < time) {
+                earliest = Some((index, event.time));
+            }
+        }
+
+        let (index, _) = earliest?;
+        self.events[index].pop().map(|event| event.event)
+    }
+
+    /// Restarts the random sequence noise is generated from. Renders with the
+    /// same seed, parameters and events are identical.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+        self.global_lfos = global_lfos(&mut self.rng);
+    }
+
+    /// Switches to a new sample rate. Voices keep playing: envelope
+    /// positions are counted in samples and are rescaled to the new rate,
+    /// the MSEG and LFOs keep their time in seconds and phases are relative
+    /// to the cycle.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let ratio = sample_rate / self.sample_rate;
+        for voice in self.voices.iter_mut() {
+            for envelope in voice.envelopes.iter_mut() {
+                envelope.position *= ratio;
+            }
+        }
+        self.sample_rate = sample_rate;
+    }
+
+    /// Renders the next buffer of interleaved samples, playing the queued
+    /// events that fall inside it.
+    ///
+    /// `channels` must be at least 1 and `buffer` must hold whole frames of
+    /// `channels` samples. Debug builds panic otherwise. Release builds
+    /// render nothing for zero channels, and leave a trailing partial frame
+    /// silent without advancing the clock for it.
+    #[inline(always)]
+    pub fn process(&mut self, buffer: &mut [f32], channels: usize) {
+        debug_assert!(channels > 0, "no channels");
+        debug_assert!(
+            buffer.len().is_multiple_of(channels),
+            "{} samples are not whole frames of {channels} channels",
+            buffer.len()
+        );
+        if channels == 0 {
+            return;
+        }
+        let whole = buffer.len() - buffer.len() % channels;
+        buffer[whole..].fill(0.0);
+
+        for envelope in self.envelopes.iter_mut() {
+            envelope.update();
+        }
+        self.voices.update();
+        self.velocity.update();
+
+        // A queue being set is picked up on a later buffer.
+        for (index, slot) in self.shared_queues.iter() {
+            if let Ok(mut slot) = slot.try_lock() {
+                slot.swap(&mut self.events[*index]);
+            }
+        }
+
+        let pitch_bend_range = self.pitch_bend_range_a.load(Ordering::Acquire);
+        if pitch_bend_range != self.pitch_bend_range {
+            self.pitch_bend_range = pitch_bend_range;
+            self.update_bend();
+        }
+
+        let fgain = self.gain_a.load(Ordering::Acquire);
+
+        for osc in self.oscs.iter_mut() {
+            osc.update();
+        }
+        self.modulation.update();
+        self.fm.update();
+        self.filter.update();
+        self.mseg.update();
+        for lfo in self.lfos.iter_mut() {
+            lfo.update();
+        }
+        let tempo = self.tempo_a.load(Ordering::Acquire);
+        let lfo_dt = self
+            .lfos
+            .each_ref()
+            .map(|lfo| lfo.freq(tempo) / self.sample_rate);
+
+        for (offset, sample_frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            let now = self.clock + offset as u64;
+            while let Some(event) = self.next_event(now) {
+                self.handle_event(event);
+            }
+
+            let mut global_lfos = [0.0; LFOS];
+            for (index, lfo) in self.lfos.iter().enumerate() {
+                if lfo.mode == LfoMode::Global {
+                    global_lfos[index] = lfo.tick(&mut self.global_lfos[index], lfo_dt[index]);
+                }
+            }
+
+            let mut sum_amps: f32 = 0.0;
+
+            let mut left: f32 = 0.0;
+            let mut right: f32 = 0.0;
+            for voice in self.voices.iter_mut() {
+                for (envelope, adsr) in voice.envelopes.iter_mut().zip(self.envelopes.iter()) {
+                    envelope.tick(self.sample_rate, adsr);
+                }
+                let mseg = self.mseg.tick(&mut voice.mseg, self.sample_rate);
+                let mut lfos = [0.0; LFOS];
+                for (index, lfo) in self.lfos.iter().enumerate() {
+                    let state = &mut voice.lfos[index];
+                    let value = match lfo.mode {
+                        LfoMode::Voice => {
+                            let rate = voice.targets.get(ModDestination::LFO_RATE[index]);
+                            let dt = if rate == 0.0 {
+                                lfo_dt[index]
+                            } else {
+                                lfo_dt[index] * 2.0f32.powf(rate)
+                            };
+                            lfo.tick(state, dt)
+                        }
+                        LfoMode::Global => global_lfos[index],
+                    };
+                    let depth =
+                        (1.0 + voice.targets.get(ModDestination::LFO_DEPTH[index])).max(0.0);
+                    lfos[index] = depth * value * lfo.fade_in(state, self.sample_rate);
+                }
+                let mut amplitude = voice.envelopes[AMP_ENVELOPE].amplitude;
+                if amplitude == 0.0 {
+                    continue;
+                }
+
+                let mut sources = Sources::default();
+                sources.set(ModSource::Velocity, voice.velocity);
+                sources.set(ModSource::ModWheel, self.controllers.mod_wheel);
+                sources.set(
+                    ModSource::Aftertouch,
+                    voice.pressure.max(self.controllers.pressure),
+                );
+                sources.set(ModSource::Mseg, mseg);
+                for (source, value) in ModSource::LFO.into_iter().zip(lfos) {
+                    sources.set(source, value);
+                }
+                let targets = self.modulation.apply(&sources);
+                voice.targets = targets;
+                amplitude *= (1.0 + targets.get(ModDestination::Amplitude)).max(0.0);
+
+                let mut freq = voice.key.freq() * self.bend;
+                let pitch = targets.get(ModDestination::Pitch);
+                if pitch != 0.0 {
+                    freq *= 2.0f32.powf(pitch / 12.0);
+                }
+                let dt = freq / self.sample_rate;
+
+                let mut voice_l = 0.0;
+                let mut voice_r = 0.0;
+
+                // Every oscillator is modulated by the others' previous
+                // sample, so any routing works, including feedback.
+                let fm = [0, 1, 2].map(|to| {
+                    let depth = (1.0 + targets.get(ModDestination::FM[to])).max(0.0);
+                    depth * self.fm.input(&voice.oscs, to)
+                });
+
+                for (index, osc) in self.oscs.iter().enumerate() {
+                    // Sources later in the order are a sample behind.
+                    let source = |other: OscRef| {
+                        other
+                            .index()
+                            .filter(|&other| other != index && self.oscs[other].active)
+                    };
+                    let inputs = Inputs {
+                        fm_mode: self.fm.mode,
+                        fm: fm[index],
+                        sync: source(osc.sync).and_then(|other| voice.oscs[other].wrapped),
+                        ring: source(osc.ring).map(|other| voice.oscs[other].output[0]),
+                    };
+
+                    let state = &mut voice.oscs[index];
+                    if !osc.active {
+                        state.output = [0.0; 2];
+                        continue;
+                    }
+
+                    state.pulse_width_mod = targets.get(ModDestination::PULSE_WIDTH[index]);
+                    state.position_mod = targets.get(ModDestination::POSITION[index]);
+                    state.gain_mod = targets.get(ModDestination::GAIN[index]);
+                    state.detune_mod = targets.get(ModDestination::DETUNE[index]);
+                    state.stereo_mod = targets.get(ModDestination::SPREAD[index]);
+                    let semitones = targets.get(ModDestination::TUNE[index])
+                        + targets.get(ModDestination::FINE[index]) / 100.0;
+                    let ratio = if semitones == 0.0 {
+                        osc.ratio
+                    } else {
+                        osc.ratio * 2.0f32.powf(semitones / 12.0)
+                    };
+
+                    sum_amps += amplitude * osc.gain(state);
+                    let (l, r) = osc.render(state, dt * ratio, inputs);
+                    voice_l += l;
+                    voice_r += r;
+                }
+
+                if self.filter.active {
+                    (voice_l, voice_r) = self.filter.tick(
+                        &mut voice.filter,
+                        (voice_l, voice_r),
+                        self.sample_rate,
+                        targets.get(ModDestination::Cutoff)
+                            + self.filter.offset(
+                                voice.key,
+                                voice.envelopes[FILTER_ENVELOPE].amplitude,
+                                targets.get(ModDestination::FilterEnvelope),
+                            ),
+                        targets.get(ModDestination::Resonance),
+                        targets.get(ModDestination::FilterDrive),
+                    );
+                }
+
+                let pan = targets.get(ModDestination::Pan).clamp(-1.0, 1.0);
+                left += amplitude * (1.0 - pan).min(1.0) * voice_l;
+                right += amplitude * (1.0 + pan).min(1.0) * voice_r;
+            }
+            self.voices.reap();
+
+            let gain = fgain / 1.0f32.max(sum_amps);
+            let left = gain * left;
+            let right = gain * right;
+            let mid = 0.5 * (left + right);
+
+            match sample_frame {
+                [mono] => *mono = mid,
+                [l, r, rest @ ..] => {
+                    *l = left;
+                    *r = right;
+                    // Channels beyond stereo get the mono mix.
+                    for sample in rest {
+                        *sample = mid;
+                    }
+                }
+                [] => {}
+            }
+        }
+
+        self.clock += (buffer.len() / channels) as u64;
+        self.clock_a.store(self.clock, Ordering::Release);
+    }
+}
+
+fn global_lfos(rng: &mut Rng) -> [LfoState; LFOS] {
+    std::array::from_fn(|_| LfoState::new(rng.next_u64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keyboard::Key, lfo::LfoShape};
+
+    /// Amplitude envelope of the only voice after playing C4 for `frames`
+    /// at each of the given rates in turn.
+    fn envelope_after(rates: &[(f32, usize)]) -> f32 {
+        let params = Params::default();
+        params.envelope.attack.store(1.0, Ordering::Release);
+        let (mut engine, mut sender) = Engine::with_queue(rates[0].0, params);
+        sender.send(Event::NoteOn {
+            key: Key::C4,
+            velocity: 1.0,
+        });
+        for &(sample_rate, frames) in rates {
+            engine.set_sample_rate(sample_rate);
+            engine.process(&mut vec![0.0; frames], 1);
+        }
+        let voice = engine.voices.iter_mut().next().unwrap();
+        voice.amp_envelope().amplitude
+    }
+
+    #[test]
+    fn envelopes_keep_their_time_across_rate_changes() {
+        let reference = envelope_after(&[(48000.0, 36000)]);
+        let switched = envelope_after(&[(48000.0, 24000), (96000.0, 24000)]);
+        assert!(
+            (reference - switched).abs() < 1e-3,
+            "{reference} != {switched}"
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "whole frames")]
+    fn rejects_partial_frames() {
+        let (mut engine, _) = Engine::with_queue(48000.0, Params::default());
+        engine.process(&mut [0.0; 5], 2);
+    }
+
+    #[test]
+    fn velocity_pans_the_voice() {
+        let params = Params::default();
+        let slot = &params.modulation[0];
+        slot.source.store(ModSource::Velocity, Ordering::Release);
+        slot.destination
+            .store(ModDestination::Pan, Ordering::Release);
+        slot.amount.store(-1.0, Ordering::Release);
+        let (mut engine, mut sender) = Engine::with_queue(48000.0, params);
+        sender.send(Event::NoteOn {
+            key: Key::C4,
+            velocity: 1.0,
+        });
+
+        let mut buffer = vec![0.0; 2 * 4800];
+        engine.process(&mut buffer, 2);
+        let energy =
+            |channel: usize| -> f32 { buffer.iter().skip(channel).step_by(2).map(|x| x * x).sum() };
+        assert!(energy(0) > 0.0);
+        assert_eq!(energy(1), 0.0);
+    }
+
+    #[test]
+    fn velocity_tunes_an_oscillator() {
+        // Cycles of C4 in 0.2 s, with and without an octave of tuning.
+        let cycles = |amount: f32| {
+            let params = Params::default();
+            let slot = &params.modulation[0];
+            slot.source.store(ModSource::Velocity, Ordering::Release);
+            slot.destination
+                .store(ModDestination::Osc1Tune, Ordering::Release);
+            slot.amount.store(amount, Ordering::Release);
+            let (mut engine, mut sender) = Engine::with_queue(48000.0, params);
+            sender.send(Event::NoteOn {
+                key: Key::C4,
+                velocity: 1.0,
+            });
+
+            let mut buffer = vec![0.0; 9600];
+            engine.process(&mut buffer, 1);
+            let rising = buffer.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0);
+            rising.count()
+        };
+        assert_eq!(cycles(0.0), 52);
+        assert_eq!(cycles(1.0), 104);
+    }
+
+    #[test]
+    fn voice_lfos_restart_and_global_lfos_run_free() {
+        // Energy of the left and right channel for 0.1 s of a note started
+        // 0.6 s in, panned by a 1 Hz square LFO.
+        let energy = |mode: LfoMode| {
+            let params = Params::default();
+            let lfo = &params.lfos[0];
+            lfo.shape.store(LfoShape::Square, Ordering::Release);
+            lfo.rate.store(1.0, Ordering::Release);
+            lfo.mode.store(mode, Ordering::Release);
+            let slot = &params.modulation[0];
+            slot.source.store(ModSource::Lfo1, Ordering::Release);
+            slot.destination
+                .store(ModDestination::Pan, Ordering::Release);
+            slot.amount.store(1.0, Ordering::Release);
+            let (mut engine, mut sender) = Engine::with_queue(48000.0, params);
+
+            engine.process(&mut vec![0.0; 2 * 28800], 2);
+            sender.send(Event::NoteOn {
+                key: Key::C4,
+                velocity: 1.0,
+            });
+            let mut buffer = vec![0.0; 2 * 4800];
+            engine.process(&mut buffer, 2);
+            let energy = |channel: usize| -> f32 {
+                buffer.iter().skip(channel).step_by(2).map(|x| x * x).sum()
+            };
+            (energy(0), energy(1))
+        };
+
+        // A voice LFO starts its cycle with the note, high and to the right.
+        let (left, right) = energy(LfoMode::Voice);
+        assert_eq!(left, 0.0);
+        assert!(right > 0.0);
+        // The global one is in the low half of its cycle by then.
+        let (left, right) = energy(LfoMode::Global);
+        assert!(left > 0.0);
+        assert_eq!(right, 0.0);
+    }
+}
@@ -15,46 +15,353 @@
  * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
-use crate::atomicf::AtomicF32;
+use crate::{atomicf::AtomicF32, params::Envelope};
 
+/// Number of envelopes each voice runs.
+pub const ENVELOPES: usize = 2;
+/// Index of the amplitude envelope, which decides how long a voice sounds.
+pub const AMP_ENVELOPE: usize = 0;
+/// Index of the filter envelope.
+pub const FILTER_ENVELOPE: usize = 1;
+
+/// How far a segment has come at `t` in `0.0..=1.0`, shaped by `curvature`
+/// in `-1.0..=1.0`. Zero is linear, negative values move fast at first and
+/// settle slowly like an exponential decay, positive values start slowly.
+#[inline(always)]
+pub fn curve(t: f32, curvature: f32) -> f32 {
+    if curvature.abs() < 1e-3 {
+        return t;
+    }
+
+    let steepness = 6.0 * curvature;
+    (steepness * t).exp_m1() / steepness.exp_m1()
+}
+
+/// Envelope settings: delay, attack, hold, decay, sustain and release, times
+/// in seconds.
 #[allow(clippy::upper_case_acronyms)]
 pub struct ADSR {
+    pub delay: f32,
     pub attack: f32,
+    pub hold: f32,
     pub decay: f32,
     pub sustain: f32,
     pub release: f32,
+    pub attack_curve: f32,
+    pub decay_curve: f32,
+    pub release_curve: f32,
+    /// Go back to the attack at the end of the decay while the key is held.
+    pub looping: bool,
+    delay_a: Arc<AtomicF32>,
     attack_a: Arc<AtomicF32>,
+    hold_a: Arc<AtomicF32>,
     decay_a: Arc<AtomicF32>,
     sustain_a: Arc<AtomicF32>,
     release_a: Arc<AtomicF32>,
+    attack_curve_a: Arc<AtomicF32>,
+    decay_curve_a: Arc<AtomicF32>,
+    release_curve_a: Arc<AtomicF32>,
+    looping_a: Arc<AtomicBool>,
 }
 
 impl ADSR {
-    pub fn new(
-        attack_a: Arc<AtomicF32>,
-        decay_a: Arc<AtomicF32>,
-        sustain_a: Arc<AtomicF32>,
-        release_a: Arc<AtomicF32>,
-    ) -> Self {
+    pub fn new(envelope: Envelope) -> Self {
         Self {
+            delay: 0.0,
             attack: 0.0,
+            hold: 0.0,
             decay: 0.0,
             sustain: 1.0,
             release: 0.0,
-            attack_a,
-            decay_a,
-            sustain_a,
-            release_a,
+            attack_curve: 0.0,
+            decay_curve: 0.0,
+            release_curve: 0.0,
+            looping: false,
+            delay_a: envelope.delay,
+            attack_a: envelope.attack,
+            hold_a: envelope.hold,
+            decay_a: envelope.decay,
+            sustain_a: envelope.sustain,
+            release_a: envelope.release,
+            attack_curve_a: envelope.attack_curve,
+            decay_curve_a: envelope.decay_curve,
+            release_curve_a: envelope.release_curve,
+            looping_a: envelope.looping,
         }
     }
 
     #[inline(always)]
     pub fn update(&mut self) {
+        self.delay = self.delay_a.load(Ordering::Acquire);
         self.attack = self.attack_a.load(Ordering::Acquire);
+        self.hold = self.hold_a.load(Ordering::Acquire);
         self.decay = self.decay_a.load(Ordering::Acquire);
         self.sustain = self.sustain_a.load(Ordering::Acquire);
         self.release = self.release_a.load(Ordering::Acquire);
+        self.attack_curve = self.attack_curve_a.load(Ordering::Acquire);
+        self.decay_curve = self.decay_curve_a.load(Ordering::Acquire);
+        self.release_curve = self.release_curve_a.load(Ordering::Acquire);
+        self.looping = self.looping_a.load(Ordering::Acquire);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyState {
+    Delay,
+    /// Attack.
+    Pressed,
+    Hold,
+    Decay,
+    Sustain,
+    Released,
+}
+
+#[derive(Clone, Copy)]
+pub struct TrackElement {
+    pub state: KeyState,
+    pub amplitude: f32,
+    /// Samples into the current stage.
+    pub position: f32,
+    /// Amplitude the current attack or release started from.
+    pub t_amplitude: f32,
+    /// Peak level of the current note, set from its velocity.
+    pub level: f32,
+}
+
+impl TrackElement {
+    #[inline(always)]
+    pub fn press(&mut self, level: f32) {
+        self.state = KeyState::Delay;
+        self.position = 0.0;
+        self.t_amplitude = self.amplitude;
+        self.level = level;
+    }
+
+    #[inline(always)]
+    pub fn release(&mut self) {
+        if self.state != KeyState::Released {
+            self.state = KeyState::Released;
+            self.position = 0.0;
+            self.t_amplitude = self.amplitude;
+        }
+    }
+
+    #[inline(always)]
+    pub fn tick(&mut self, sample_rate: f32, adsr: &ADSR) {
+        match self.state {
+            KeyState::Delay => {
+                self.position += 1.0;
+                if self.position < sample_rate * adsr.delay {
+                    return;
+                }
+
+                self.position = 0.0;
+                self.state = KeyState::Pressed;
+            }
+            KeyState::Pressed => {
+                self.position += 1.0;
+                let length = sample_rate * adsr.attack;
+                if self.position < length {
+                    let t = curve(self.position / length, adsr.attack_curve);
+                    self.amplitude = self.t_amplitude + (self.level - self.t_amplitude) * t;
+                    return;
+                }
+
+                self.amplitude = self.level;
+                self.position = 0.0;
+                self.state = KeyState::Hold;
+            }
+            KeyState::Hold => {
+                self.position += 1.0;
+                if self.position < sample_rate * adsr.hold {
+                    return;
+                }
+
+                self.position = 0.0;
+                self.state = KeyState::Decay;
+            }
+            KeyState::Decay => {
+                self.position += 1.0;
+                let length = sample_rate * adsr.decay;
+                if self.position < length {
+                    let t = curve(self.position / length, adsr.decay_curve);
+                    self.amplitude = self.level * (1.0 - (1.0 - adsr.sustain) * t);
+                    return;
+                }
+
+                self.amplitude = self.level * adsr.sustain;
+                if adsr.looping {
+                    self.position = 0.0;
+                    self.t_amplitude = self.amplitude;
+                    self.state = KeyState::Pressed;
+                } else {
+                    self.state = KeyState::Sustain;
+                }
+            }
+            KeyState::Sustain => {
+                self.amplitude = self.level * adsr.sustain;
+            }
+            KeyState::Released => {
+                // Starts from wherever the amplitude was, so releasing in
+                // the middle of another segment does not jump.
+                self.position += 1.0;
+                let length = sample_rate * adsr.release;
+                if self.position < length {
+                    let t = curve(self.position / length, adsr.release_curve);
+                    self.amplitude = self.t_amplitude * (1.0 - t);
+                } else {
+                    self.amplitude = 0.0;
+                }
+            }
+        }
+    }
+
+    /// True once the release ramp has reached silence.
+    #[inline(always)]
+    pub fn is_finished(&self) -> bool {
+        self.state == KeyState::Released && self.amplitude <= 0.0
+    }
+}
+
+impl Default for TrackElement {
+    fn default() -> Self {
+        Self {
+            state: KeyState::Released,
+            amplitude: 0.0,
+            position: 0.0,
+            t_amplitude: 0.0,
+            level: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn adsr(attack: f32, decay: f32, sustain: f32, release: f32) -> ADSR {
+        let mut adsr = ADSR::new(Envelope::new(attack, decay, sustain, release));
+        adsr.update();
+        adsr
+    }
+
+    /// Amplitudes of the next `ticks` samples.
+    fn run(envelope: &mut TrackElement, adsr: &ADSR, ticks: usize) -> Vec<f32> {
+        (0..ticks)
+            .map(|_| {
+                envelope.tick(SAMPLE_RATE, adsr);
+                envelope.amplitude
+            })
+            .collect()
+    }
+
+    #[test]
+    fn curves_hit_their_endpoints() {
+        for curvature in [-1.0, -0.5, 0.0, 0.5, 1.0] {
+            assert_eq!(curve(0.0, curvature), 0.0);
+            assert!((curve(1.0, curvature) - 1.0).abs() < 1e-6, "{curvature}");
+        }
+        assert!(curve(0.5, -0.5) > 0.5);
+        assert!(curve(0.5, 0.5) < 0.5);
+    }
+
+    #[test]
+    fn segments_reach_their_levels_for_any_curve() {
+        for curvature in [-1.0, 0.0, 1.0] {
+            let mut adsr = adsr(0.1, 0.1, 0.25, 0.1);
+            adsr.attack_curve = curvature;
+            adsr.decay_curve = curvature;
+            adsr.release_curve = curvature;
+            let mut envelope = TrackElement::default();
+            envelope.press(0.8);
+
+            let levels = run(&mut envelope, &adsr, 250);
+            // An empty delay and hold take one sample each to pass.
+            assert!(levels[99] < 0.8, "{curvature}");
+            assert_eq!(levels[100], 0.8, "{curvature}");
+            assert!(levels[200] > 0.2, "{curvature}");
+            assert_eq!(levels[201], 0.2, "{curvature}");
+            assert!(levels[..101].windows(2).all(|pair| pair[1] >= pair[0]));
+            assert!(levels[101..202].windows(2).all(|pair| pair[1] <= pair[0]));
+
+            envelope.release();
+            let levels = run(&mut envelope, &adsr, 100);
+            assert_eq!(levels[99], 0.0, "{curvature}");
+            assert!(envelope.is_finished());
+        }
+    }
+
+    #[test]
+    fn stages_take_their_time() {
+        let mut adsr = adsr(0.02, 0.04, 0.5, 0.1);
+        adsr.delay = 0.01;
+        adsr.hold = 0.03;
+        let mut envelope = TrackElement::default();
+        envelope.press(1.0);
+
+        let states: Vec<(KeyState, f32)> = (0..120)
+            .map(|_| {
+                envelope.tick(SAMPLE_RATE, &adsr);
+                (envelope.state, envelope.amplitude)
+            })
+            .collect();
+        let first = |state| states.iter().position(|&(s, _)| s == state).unwrap();
+
+        assert!(states[..10].iter().all(|&(_, amplitude)| amplitude == 0.0));
+        assert_eq!(first(KeyState::Pressed), 9);
+        assert_eq!(first(KeyState::Hold), 29);
+        assert!(states[29..59]
+            .iter()
+            .all(|&(_, amplitude)| amplitude == 1.0));
+        assert_eq!(first(KeyState::Decay), 59);
+        assert_eq!(first(KeyState::Sustain), 99);
+        assert_eq!(states[119], (KeyState::Sustain, 0.5));
+    }
+
+    #[test]
+    fn loops_while_held() {
+        let mut adsr = adsr(0.02, 0.02, 0.5, 0.1);
+        adsr.looping = true;
+        let mut envelope = TrackElement::default();
+        envelope.press(1.0);
+
+        let levels = run(&mut envelope, &adsr, 200);
+        assert_ne!(envelope.state, KeyState::Sustain);
+        let peaks = levels.iter().filter(|&&level| level == 1.0).count();
+        let troughs = levels.iter().filter(|&&level| level == 0.5).count();
+        assert!(
+            peaks >= 4 && troughs >= 4,
+            "{peaks} peaks, {troughs} troughs"
+        );
+
+        envelope.release();
+        run(&mut envelope, &adsr, 100);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn releases_mid_attack_without_a_jump() {
+        let adsr = adsr(0.1, 0.1, 1.0, 0.1);
+        let mut envelope = TrackElement::default();
+        envelope.press(1.0);
+        let before = *run(&mut envelope, &adsr, 50).last().unwrap();
+        assert!(before > 0.4 && before < 0.6);
+
+        envelope.release();
+        let levels = run(&mut envelope, &adsr, 100);
+        assert!(
+            (levels[0] - before).abs() < 0.02,
+            "{before} -> {}",
+            levels[0]
+        );
+        assert!(levels.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(envelope.is_finished());
     }
 }
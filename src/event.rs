@@ -0,0 +1,150 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    keyboard::Key,
+    ringbuf::{self, Consumer, Producer},
+};
+
+/// Number of events that can be queued before the audio thread picks them up.
+pub const QUEUE_CAPACITY: usize = 1024;
+
+/// Controller numbers the engine reacts to.
+pub mod cc {
+    pub const MOD_WHEEL: u8 = 1;
+    pub const ALL_SOUND_OFF: u8 = 120;
+    pub const ALL_NOTES_OFF: u8 = 123;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    /// `velocity` is in the range `0.0..=1.0`.
+    NoteOn {
+        key: Key,
+        velocity: f32,
+    },
+    NoteOff {
+        key: Key,
+    },
+    Controller {
+        number: u8,
+        value: u8,
+    },
+    /// `value` is in the range `-1.0..=1.0`.
+    PitchBend {
+        value: f32,
+    },
+    /// Channel aftertouch, `value` is in the range `0.0..=1.0`.
+    ChannelPressure {
+        value: f32,
+    },
+    /// Polyphonic aftertouch, `value` is in the range `0.0..=1.0`.
+    KeyPressure {
+        key: Key,
+        value: f32,
+    },
+}
+
+/// Latest values of the continuous controls received by the engine.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Controllers {
+    pub pitch_bend: f32,
+    pub mod_wheel: f32,
+    pub pressure: f32,
+}
+
+/// An event scheduled at an absolute sample frame of the engine clock.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimedEvent {
+    pub time: u64,
+    pub event: Event,
+}
+
+/// Sending half of an event queue. Events must be sent in non-decreasing time
+/// order; events whose time has already passed are played at the start of the
+/// next buffer.
+pub struct EventSender {
+    producer: Producer<TimedEvent>,
+    clock: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    /// The first sample frame of the next buffer the engine will render.
+    #[inline(always)]
+    pub fn now(&self) -> u64 {
+        self.clock.load(Ordering::Acquire)
+    }
+
+    /// Queues `event` at `time`. Returns false if the queue is full and the
+    /// event was dropped.
+    pub fn send_at(&mut self, time: u64, event: Event) -> bool {
+        self.producer.push(TimedEvent { time, event }).is_ok()
+    }
+
+    /// Queues `event` to be played as soon as possible.
+    pub fn send(&mut self, event: Event) -> bool {
+        self.send_at(self.now(), event)
+    }
+}
+
+/// Creates an event queue. The clock is advanced by the engine owning the
+/// receiving half.
+pub fn queue(clock: Arc<AtomicU64>) -> (EventSender, Consumer<TimedEvent>) {
+    let (producer, consumer) = ringbuf::channel(QUEUE_CAPACITY);
+    (EventSender { producer, clock }, consumer)
+}
+
+/// A queue that replaces one the engine reads from while it runs, see
+/// [`Engine::share_queue`](crate::engine::Engine::share_queue).
+pub type SharedQueueSlot = Arc<Mutex<QueueSlot>>;
+
+/// The next queue for a shared event source. The engine hands back the
+/// queue it stops reading instead of dropping it, so freeing it happens on
+/// the thread that sets the next one.
+#[derive(Default)]
+pub struct QueueSlot {
+    queue: Option<Consumer<TimedEvent>>,
+    retired: Option<Consumer<TimedEvent>>,
+}
+
+impl QueueSlot {
+    /// Replaces the queue the engine reads next. Events still waiting in the
+    /// old queue are dropped.
+    pub fn set(&mut self, queue: Consumer<TimedEvent>) {
+        self.queue = Some(queue);
+        self.retired = None;
+    }
+
+    /// Swaps `reading` for a newly set queue, keeping the old one here.
+    /// Waits while an earlier queue has not been freed yet.
+    #[inline(always)]
+    pub(crate) fn swap(&mut self, reading: &mut Consumer<TimedEvent>) {
+        if self.retired.is_none() {
+            if let Some(queue) = self.queue.take() {
+                self.retired = Some(mem::replace(reading, queue));
+            }
+        }
+    }
+}
@@ -0,0 +1,541 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Per-voice filters: a clean state variable filter and a nonlinear ladder.
+
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    atomicf::{AtomicF32, AtomicFilterMode, AtomicFilterType, AtomicSlope},
+    keyboard::Key,
+    params,
+};
+
+/// Lowest and highest cutoff in Hz.
+pub const CUTOFF_RANGE: (f32, f32) = (20.0, 20000.0);
+
+/// Octaves the cutoff moves at full envelope amount.
+pub const ENVELOPE_RANGE: f32 = 8.0;
+
+/// Odd taps `h[1], h[3], ..` of the half-band FIR that upsamples the ladder
+/// input to twice the sample rate and filters its output before decimation.
+/// The centre tap is 0.5 and the other even taps are zero. A Kaiser-windowed
+/// sinc (beta 7), flat within 0.03 dB up to 19 kHz and at least 72 dB down
+/// from 29 kHz at a 48 kHz sample rate.
+const HALF_BAND: [f32; 12] = [
+    0.3165601,
+    -0.10085961,
+    0.0552395,
+    -0.03433167,
+    0.02207986,
+    -0.014130858,
+    0.008787184,
+    -0.005204281,
+    0.0028707597,
+    -0.0014283092,
+    0.0006044144,
+    -0.00018709154,
+];
+
+/// Length of the half-band histories, one phase of the FIR.
+const HALF_BAND_LEN: usize = 2 * HALF_BAND.len();
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FilterType {
+    /// State variable filter with selectable mode.
+    Svf = 1,
+    /// Four-pole transistor ladder low-pass with drive.
+    Ladder = 2,
+}
+
+impl From<i32> for FilterType {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Svf,
+            2 => Self::Ladder,
+            _ => panic!("Invalid filter type integer"),
+        }
+    }
+}
+
+impl FilterType {
+    pub const ALL: [Self; 2] = [Self::Svf, Self::Ladder];
+}
+
+/// Roll-off of the ladder filter.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Slope {
+    Db12 = 1,
+    Db24 = 2,
+}
+
+impl From<i32> for Slope {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Db12,
+            2 => Self::Db24,
+            _ => panic!("Invalid slope integer"),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FilterMode {
+    LowPass = 1,
+    HighPass = 2,
+    BandPass = 3,
+    Notch = 4,
+}
+
+impl From<i32> for FilterMode {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::LowPass,
+            2 => Self::HighPass,
+            3 => Self::BandPass,
+            4 => Self::Notch,
+            _ => panic!("Invalid filter mode integer"),
+        }
+    }
+}
+
+impl FilterMode {
+    pub const ALL: [Self; 4] = [Self::LowPass, Self::HighPass, Self::BandPass, Self::Notch];
+}
+
+/// Per-voice filter state, left and right.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FilterState {
+    ic1eq: [f32; 2],
+    ic2eq: [f32; 2],
+    /// Integrator states of the ladder stages.
+    stages: [[f32; 4]; 2],
+    /// Input history of the ladder's upsampler.
+    ladder_input: [[f32; HALF_BAND_LEN]; 2],
+    /// Even oversampled ladder outputs, delayed to line up with the odd ones.
+    ladder_even: [[f32; HALF_BAND.len()]; 2],
+    /// Odd oversampled ladder outputs, for the decimation filter.
+    ladder_odd: [[f32; HALF_BAND_LEN]; 2],
+}
+
+/// Shifts `sample` into the end of `history`.
+#[inline(always)]
+fn push(history: &mut [f32], sample: f32) {
+    history.copy_within(1.., 0);
+    history[history.len() - 1] = sample;
+}
+
+/// The odd taps of the half-band FIR over a history centred between its two
+/// middle samples.
+#[inline(always)]
+fn half_band(history: &[f32; HALF_BAND_LEN]) -> f32 {
+    let middle = HALF_BAND.len();
+    HALF_BAND
+        .iter()
+        .enumerate()
+        .map(|(i, tap)| tap * (history[middle - 1 - i] + history[middle + i]))
+        .sum()
+}
+
+/// The filter section of a voice. The state variable filter is in the
+/// trapezoidal (TPT) form, which stays stable when cutoff and resonance change
+/// every sample. The ladder saturates its input and feedback and runs at twice
+/// the sample rate, between half-band FIR filters, to keep the distortion from
+/// aliasing. This delays its output by 23 samples.
+pub struct Filter {
+    pub active: bool,
+    pub kind: FilterType,
+    pub mode: FilterMode,
+    pub slope: Slope,
+    /// Ladder input gain, `0.0..=1.0`.
+    pub drive: f32,
+    /// Cutoff in Hz.
+    pub cutoff: f32,
+    /// `0.0..=1.0`, self-oscillation is approached at 1.0.
+    pub resonance: f32,
+    /// `-1.0..=1.0` of [`ENVELOPE_RANGE`].
+    pub envelope_amount: f32,
+    pub velocity: f32,
+    pub key_track: f32,
+    active_a: Arc<AtomicBool>,
+    kind_a: Arc<AtomicFilterType>,
+    mode_a: Arc<AtomicFilterMode>,
+    slope_a: Arc<AtomicSlope>,
+    drive_a: Arc<AtomicF32>,
+    cutoff_a: Arc<AtomicF32>,
+    resonance_a: Arc<AtomicF32>,
+    envelope_amount_a: Arc<AtomicF32>,
+    velocity_a: Arc<AtomicF32>,
+    key_track_a: Arc<AtomicF32>,
+}
+
+impl Filter {
+    pub fn new(filter: params::Filter) -> Self {
+        Self {
+            active: false,
+            kind: FilterType::Svf,
+            mode: FilterMode::LowPass,
+            slope: Slope::Db24,
+            drive: 0.0,
+            cutoff: CUTOFF_RANGE.1,
+            resonance: 0.0,
+            envelope_amount: 0.0,
+            velocity: 0.0,
+            key_track: 0.0,
+            active_a: filter.active,
+            kind_a: filter.kind,
+            mode_a: filter.mode,
+            slope_a: filter.slope,
+            drive_a: filter.drive,
+            cutoff_a: filter.cutoff,
+            resonance_a: filter.resonance,
+            envelope_amount_a: filter.envelope_amount,
+            velocity_a: filter.velocity,
+            key_track_a: filter.key_track,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        self.active = self.active_a.load(Ordering::Acquire);
+        self.kind = self.kind_a.load(Ordering::Acquire);
+        self.mode = self.mode_a.load(Ordering::Acquire);
+        self.slope = self.slope_a.load(Ordering::Acquire);
+        self.drive = self.drive_a.load(Ordering::Acquire);
+        self.cutoff = self.cutoff_a.load(Ordering::Acquire);
+        self.resonance = self.resonance_a.load(Ordering::Acquire);
+        self.envelope_amount = self.envelope_amount_a.load(Ordering::Acquire);
+        self.velocity = self.velocity_a.load(Ordering::Acquire);
+        self.key_track = self.key_track_a.load(Ordering::Acquire);
+    }
+
+    /// Peak level of the filter envelope for a note of `velocity`.
+    #[inline(always)]
+    pub fn envelope_level(&self, velocity: f32) -> f32 {
+        1.0 - self.velocity * (1.0 - velocity)
+    }
+
+    /// Cutoff shift in octaves from key tracking and the filter envelope,
+    /// with the envelope amount offset by `amount_mod`.
+    #[inline(always)]
+    pub fn offset(&self, key: Key, envelope: f32, amount_mod: f32) -> f32 {
+        let octaves_from_c4 = (key.note() as f32 - Key::C4.note() as f32) / 12.0;
+        let amount = (self.envelope_amount + amount_mod).clamp(-1.0, 1.0);
+        self.key_track * octaves_from_c4 + amount * ENVELOPE_RANGE * envelope
+    }
+
+    /// Filters one stereo sample. `cutoff` is shifted by `octaves`,
+    /// `resonance` offset by `resonance_mod` and `drive` by `drive_mod`.
+    #[inline(always)]
+    pub fn tick(
+        &self,
+        state: &mut FilterState,
+        input: (f32, f32),
+        sample_rate: f32,
+        octaves: f32,
+        resonance_mod: f32,
+        drive_mod: f32,
+    ) -> (f32, f32) {
+        // At very low sample rates the Nyquist limit falls below the range.
+        let max = CUTOFF_RANGE.1.min(0.49 * sample_rate).max(CUTOFF_RANGE.0);
+        let cutoff = (self.cutoff * 2.0f32.powf(octaves)).clamp(CUTOFF_RANGE.0, max);
+        let resonance = (self.resonance + resonance_mod).clamp(0.0, 1.0);
+
+        match self.kind {
+            FilterType::Svf => self.svf(state, input, cutoff / sample_rate, resonance),
+            FilterType::Ladder => {
+                let drive = (self.drive + drive_mod).clamp(0.0, 1.0);
+                self.ladder(state, input, cutoff / sample_rate, resonance, drive)
+            }
+        }
+    }
+
+    /// `cutoff` is relative to the sample rate.
+    #[inline(always)]
+    fn svf(
+        &self,
+        state: &mut FilterState,
+        input: (f32, f32),
+        cutoff: f32,
+        resonance: f32,
+    ) -> (f32, f32) {
+        let g = (PI * cutoff).tan();
+        // Damping, from a Q of 0.5 down to a Q of 25.
+        let k = 2.0 - 1.96 * resonance;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let mut output = [input.0, input.1];
+        for (channel, sample) in output.iter_mut().enumerate() {
+            let v0 = *sample;
+            let ic1eq = &mut state.ic1eq[channel];
+            let ic2eq = &mut state.ic2eq[channel];
+            let v3 = v0 - *ic2eq;
+            let v1 = a1 * *ic1eq + a2 * v3;
+            let v2 = *ic2eq + a2 * *ic1eq + a3 * v3;
+            *ic1eq = 2.0 * v1 - *ic1eq;
+            *ic2eq = 2.0 * v2 - *ic2eq;
+
+            *sample = match self.mode {
+                FilterMode::LowPass => v2,
+                FilterMode::HighPass => v0 - k * v1 - v2,
+                // Scaled to unity gain at the cutoff for any resonance.
+                FilterMode::BandPass => k * v1,
+                FilterMode::Notch => v0 - k * v1,
+            };
+        }
+
+        (output[0], output[1])
+    }
+
+    /// `cutoff` is relative to the sample rate.
+    #[inline(always)]
+    fn ladder(
+        &self,
+        state: &mut FilterState,
+        input: (f32, f32),
+        cutoff: f32,
+        resonance: f32,
+        drive: f32,
+    ) -> (f32, f32) {
+        // Cutoff relative to the oversampled rate.
+        let g = (PI * cutoff / 2.0).tan();
+        // Gain of one trapezoidal one-pole stage.
+        let a = g / (1.0 + g);
+        // Self-oscillates from a feedback of 4, a little below the top of the
+        // range.
+        let k = 4.2 * resonance;
+        let gain = 1.0 + 9.0 * drive;
+        let tap = match self.slope {
+            Slope::Db12 => 1,
+            Slope::Db24 => 3,
+        };
+
+        let mut output = [input.0, input.1];
+        for (channel, sample) in output.iter_mut().enumerate() {
+            let stages = &mut state.stages[channel];
+
+            // Upsample by stuffing a zero after every sample and filtering,
+            // which leaves the even samples as the delayed input and
+            // interpolates the odd ones.
+            let history = &mut state.ladder_input[channel];
+            push(history, *sample);
+            let even = history[HALF_BAND.len() - 1];
+            let odd = 2.0 * half_band(history);
+
+            let mut outputs = [even, odd];
+            for x in outputs.iter_mut() {
+                // Solve the feedback loop for the linear ladder, then
+                // saturate the input to the first stage. The saturation
+                // bounds self-oscillation.
+                let s = stages
+                    .iter()
+                    .fold(0.0, |s, stage| a * s + (1.0 - a) * stage);
+                let u = ((gain * *x - k * s) / (1.0 + k * a * a * a * a)).tanh();
+
+                let mut y = [0.0; 4];
+                let mut stage_input = u;
+                for (stage, y) in stages.iter_mut().zip(y.iter_mut()) {
+                    let v = a * (stage_input - *stage);
+                    *y = v + *stage;
+                    *stage = *y + v;
+                    stage_input = *y;
+                }
+                *x = y[tap];
+            }
+
+            // Filter with the same half-band FIR and keep every other
+            // sample. Only the centre tap falls on an even sample.
+            let [even, odd] = outputs;
+            let delayed = &mut state.ladder_even[channel];
+            push(delayed, even);
+            let history = &mut state.ladder_odd[channel];
+            push(history, odd);
+            *sample = 0.5 * delayed[0] + half_band(history);
+        }
+
+        (output[0], output[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::Rng;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    fn svf(mode: FilterMode, cutoff: f32, resonance: f32) -> Filter {
+        let mut filter = Filter::new(params::Filter::default());
+        filter.active = true;
+        filter.mode = mode;
+        filter.cutoff = cutoff;
+        filter.resonance = resonance;
+        filter
+    }
+
+    fn ladder(slope: Slope, cutoff: f32, resonance: f32, drive: f32) -> Filter {
+        let mut filter = svf(FilterMode::LowPass, cutoff, resonance);
+        filter.kind = FilterType::Ladder;
+        filter.slope = slope;
+        filter.drive = drive;
+        filter
+    }
+
+    /// Peak output for a sine of `freq` once the filter has settled.
+    fn gain(filter: &Filter, freq: f32) -> f32 {
+        let mut state = FilterState::default();
+        let len = SAMPLE_RATE as usize;
+        (0..len)
+            .map(|n| {
+                // In f64, the phase of an f32 sine drifts enough over a
+                // second to leak into the passband.
+                let t = n as f64 / SAMPLE_RATE as f64;
+                let x = (2.0 * std::f64::consts::PI * freq as f64 * t).sin() as f32;
+                filter
+                    .tick(&mut state, (x, x), SAMPLE_RATE, 0.0, 0.0, 0.0)
+                    .0
+            })
+            .skip(len / 2)
+            .fold(0.0, |peak, y: f32| peak.max(y.abs()))
+    }
+
+    #[test]
+    fn band_pass_peak_is_unity() {
+        for resonance in [0.0, 0.5, 0.9, 1.0] {
+            let filter = svf(FilterMode::BandPass, 1000.0, resonance);
+            let peak = gain(&filter, 1000.0);
+            assert!((peak - 1.0).abs() < 0.02, "resonance {resonance}: {peak}");
+            assert!(gain(&filter, 8000.0) < 0.5 * peak);
+            assert!(gain(&filter, 125.0) < 0.5 * peak);
+        }
+    }
+
+    #[test]
+    fn low_and_high_pass_attenuate_the_right_band() {
+        let low_pass = svf(FilterMode::LowPass, 1000.0, 0.0);
+        assert!((gain(&low_pass, 100.0) - 1.0).abs() < 0.02);
+        assert!(gain(&low_pass, 10000.0) < 0.02);
+
+        let high_pass = svf(FilterMode::HighPass, 1000.0, 0.0);
+        assert!(gain(&high_pass, 100.0) < 0.02);
+        assert!((gain(&high_pass, 10000.0) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn ladder_slopes_fall_by_12_and_24_db_per_octave() {
+        for (slope, per_octave) in [(Slope::Db12, 4.0), (Slope::Db24, 16.0)] {
+            let filter = ladder(slope, 500.0, 0.0, 0.0);
+            for freq in [2000.0, 4000.0] {
+                let ratio = gain(&filter, freq) / gain(&filter, 2.0 * freq);
+                assert!(
+                    (ratio / per_octave - 1.0).abs() < 0.2,
+                    "{slope:?} from {freq} Hz: {ratio}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ladder_self_oscillates_at_full_resonance() {
+        for slope in [Slope::Db12, Slope::Db24] {
+            let filter = ladder(slope, 1000.0, 1.0, 0.0);
+            let mut state = FilterState::default();
+            // A single click, then silence.
+            let output: Vec<f32> = (0..SAMPLE_RATE as usize)
+                .map(|n| {
+                    let x = if n == 0 { 1.0 } else { 0.0 };
+                    filter
+                        .tick(&mut state, (x, x), SAMPLE_RATE, 0.0, 0.0, 0.0)
+                        .0
+                })
+                .collect();
+
+            let tail = &output[output.len() / 2..];
+            let peak = tail.iter().fold(0.0f32, |peak, y| peak.max(y.abs()));
+            assert!(peak > 0.1, "{slope:?}: {peak}");
+
+            // Rings near the cutoff.
+            let crossings = tail
+                .windows(2)
+                .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+                .count();
+            let freq = crossings as f32 / 0.5;
+            assert!((800.0..1200.0).contains(&freq), "{slope:?}: {freq} Hz");
+        }
+    }
+
+    #[test]
+    fn ladder_drive_stays_bounded() {
+        for resonance in [0.0, 0.5, 1.0] {
+            let quiet = gain(&ladder(Slope::Db24, 2000.0, resonance, 0.0), 200.0);
+            let filter = ladder(Slope::Db24, 2000.0, resonance, 1.0);
+            let driven = gain(&filter, 200.0);
+            assert!(driven > quiet, "resonance {resonance}: {driven} {quiet}");
+            assert!(driven < 1.5, "resonance {resonance}: {driven}");
+
+            // Full-scale square waves, driven hardest.
+            let mut state = FilterState::default();
+            for n in 0..SAMPLE_RATE as usize {
+                let x = if n % 100 < 50 { 1.0 } else { -1.0 };
+                let (left, _) = filter.tick(&mut state, (x, x), SAMPLE_RATE, 0.0, 0.0, 0.0);
+                assert!(left.abs() < 1.5, "resonance {resonance} at {n}: {left}");
+            }
+        }
+    }
+
+    #[test]
+    fn stays_bounded_under_modulation() {
+        let mut rng = Rng::new(1);
+        for kind in FilterType::ALL {
+            for mode in FilterMode::ALL {
+                let mut filter = svf(mode, 1000.0, 0.0);
+                filter.kind = kind;
+                let mut state = FilterState::default();
+                for n in 0..SAMPLE_RATE as usize {
+                    // A full-scale square through a cutoff, resonance and
+                    // drive that jump every sample.
+                    let x = if n % 50 < 25 { 1.0 } else { -1.0 };
+                    let octaves = 12.0 * rng.next_f32();
+                    let resonance = rng.next_f32();
+                    let drive = rng.next_f32();
+                    let (left, right) =
+                        filter.tick(&mut state, (x, -x), SAMPLE_RATE, octaves, resonance, drive);
+                    assert!(
+                        left.abs() < 10.0 && right.abs() < 10.0,
+                        "{kind:?} {mode:?} at {n}: {left} {right}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn low_sample_rates_do_not_panic() {
+        let filter = svf(FilterMode::LowPass, 1000.0, 0.5);
+        let mut state = FilterState::default();
+        let (left, right) = filter.tick(&mut state, (1.0, 1.0), 30.0, 0.0, 0.0, 0.0);
+        assert!(left.is_finite() && right.is_finite());
+    }
+}
@@ -24,6 +24,10 @@ use eframe::{
 
 const ARC_ROTATION: f32 = std::f32::consts::PI / 6.0;
 
+/// Ratio between the full range of a logarithmic knob and the smallest step
+/// it resolves near the start.
+const LOG_RATIO: f32 = 10000.0;
+
 // From https://github.com/emilk/egui/blob/master/crates/egui/src/widgets/drag_value.rs#L741
 pub fn clamp_value_to_range(x: f32, range: &RangeInclusive<f32>) -> f32 {
     let (mut min, mut max) = (*range.start(), *range.end());
@@ -46,6 +50,7 @@ pub struct Knob<'a> {
     value: &'a mut f32,
     range: RangeInclusive<f32>,
     speed: f32,
+    logarithmic: bool,
 }
 
 impl<'a> Knob<'a> {
@@ -54,8 +59,38 @@ impl<'a> Knob<'a> {
             value,
             range,
             speed,
+            logarithmic: false,
+        }
+    }
+
+    /// Spreads the range out logarithmically, for times and frequencies
+    /// spanning several orders of magnitude. `speed` is then the fraction of
+    /// the knob's travel per point dragged.
+    pub fn logarithmic(mut self) -> Self {
+        self.logarithmic = true;
+        self
+    }
+
+    /// Where `value` is on the knob's travel, `0.0..=1.0`.
+    fn position(&self, value: f32) -> f32 {
+        let (start, end) = (*self.range.start(), *self.range.end());
+        let t = (clamp_value_to_range(value, &self.range) - start) / (end - start);
+        if self.logarithmic {
+            (1.0 + t * LOG_RATIO).ln() / (1.0 + LOG_RATIO).ln()
+        } else {
+            t
         }
     }
+
+    fn value(&self, position: f32) -> f32 {
+        let (start, end) = (*self.range.start(), *self.range.end());
+        let t = if self.logarithmic {
+            ((1.0 + LOG_RATIO).powf(position) - 1.0) / LOG_RATIO
+        } else {
+            position
+        };
+        start + (end - start) * t
+    }
 }
 
 fn render_arc(
@@ -105,17 +140,14 @@ impl<'a> Widget for Knob<'a> {
             let mut stroke = visuals.bg_stroke;
             stroke.color = visuals.weak_bg_fill;
             stroke.width = 4.0;
-            render_arc(&painter, &center, 0.0, arc_max, 28.0 - 4.0, &stroke);
+            render_arc(painter, &center, 0.0, arc_max, 28.0 - 4.0, &stroke);
 
-            let progress_start = arc_max
-                - arc_max
-                    * ((clamp_value_to_range(old_value, &self.range) - self.range.start())
-                        / (self.range.end() - self.range.start()));
+            let progress_start = arc_max - arc_max * self.position(old_value);
 
             let mut stroke = visuals.fg_stroke;
             stroke.width = 4.0;
             render_arc(
-                &painter,
+                painter,
                 &center,
                 progress_start,
                 arc_max,
@@ -136,6 +168,12 @@ impl<'a> Widget for Knob<'a> {
                 visuals.fg_stroke,
             );
 
+            if response.drag_started() {
+                // The value may have been changed elsewhere since the last
+                // drag.
+                ui.data_mut(|data| data.remove::<f32>(id));
+            }
+
             if response.dragged() {
                 // TODO: Change cursor
 
@@ -144,7 +182,17 @@ impl<'a> Widget for Knob<'a> {
 
                 let delta_value = delta_points * self.speed;
 
-                if delta_value != 0.0 {
+                if delta_value != 0.0 && self.logarithmic {
+                    // Track the position rather than the value, rounding
+                    // would stall the knob near the start of the range.
+                    let position = ui.data_mut(|data| data.get_temp::<f32>(id));
+                    let position = position.unwrap_or_else(|| self.position(*self.value));
+                    let position = (position + delta_value).clamp(0.0, 1.0);
+
+                    *self.value = self.value(position);
+
+                    ui.data_mut(|data| data.insert_temp::<f32>(id, position));
+                } else if delta_value != 0.0 {
                     let precise_value = ui.data_mut(|data| data.get_temp::<f32>(id));
                     let precise_value = precise_value.unwrap_or(*self.value);
                     let precise_value = precise_value + delta_value;
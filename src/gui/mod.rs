@@ -15,19 +15,44 @@
  * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::atomic::Ordering;
+use std::{path::Path, sync::atomic::Ordering, time::Duration};
 
-use eframe::egui::{self, DragValue, Margin, Theme, Ui};
+use eframe::egui::{self, DragValue, Margin, Pos2, Sense, Shape, Theme, Ui, Vec2};
 use knob::Knob;
+use mseg_editor::MsegEditor;
 
 use crate::{
-    keyboard::{Key, KeyBitflags, Keyboard, Osc},
+    envelope::curve,
+    filter::{FilterMode, FilterType, Slope, CUTOFF_RANGE},
+    keyboard::{Key, Keyboard},
+    lfo::{Division, LfoMode, LfoShape, RATE_RANGE},
+    midi::MidiBackend,
+    modulation::{ModDestination, ModSource},
+    oscilator::{FmMode, OscRef, Quality, MAX_UNISON},
+    params::{Envelope, Lfo, Osc, Params},
+    synthesizer,
+    velocity::VelocityCurve,
+    voice::{StealPolicy, MAX_VOICES},
     waveform::Waveform,
+    wavetable::{self, Wavetable},
 };
 
 mod knob;
+mod mseg_editor;
+
+/// Longest envelope segment the knobs reach, in seconds.
+const ENVELOPE_TIME: f32 = 30.0;
+
+/// GUI state of an oscillator panel.
+#[derive(Default)]
+struct OscState {
+    wavetable_path: String,
+    wavetable_error: Option<String>,
+}
+
+fn osc_ui(ui: &mut Ui, osc: &mut Osc, index: usize, state: &mut OscState) {
+    let label = format!("Oscillator {}", index + 1);
 
-fn osc_ui(ui: &mut Ui, osc: &mut Osc, label: &str) {
     egui::Frame::default()
         .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
         .inner_margin(Margin::same(5.0))
@@ -36,7 +61,7 @@ fn osc_ui(ui: &mut Ui, osc: &mut Osc, label: &str) {
             ui.vertical(|ui| {
                 let mut active = osc.active.load(Ordering::Acquire);
                 ui.horizontal(|ui| {
-                    ui.checkbox(&mut active, label);
+                    ui.checkbox(&mut active, &label);
                 });
                 osc.active.store(active, Ordering::Release);
 
@@ -51,6 +76,22 @@ fn osc_ui(ui: &mut Ui, osc: &mut Osc, label: &str) {
                         ui.add(Knob::new(&mut gain, 0.0..=1.0, 0.01));
                         ui.add(DragValue::new(&mut gain).range(0.0..=1.0).speed(0.01));
                         osc.gain.store(gain, Ordering::Release);
+
+                        match osc.waveform.load(Ordering::Acquire) {
+                            Waveform::Wavetable => {
+                                let mut position = osc.position.load(Ordering::Acquire);
+                                ui.label("Position");
+                                ui.add(Knob::new(&mut position, 0.0..=1.0, 0.01));
+                                osc.position.store(position, Ordering::Release);
+                            }
+                            Waveform::Square => {
+                                let mut width = osc.pulse_width.load(Ordering::Acquire);
+                                ui.label("Width");
+                                ui.add(Knob::new(&mut width, 0.01..=0.99, 0.01));
+                                osc.pulse_width.store(width, Ordering::Release);
+                            }
+                            _ => {}
+                        }
                     });
 
                     columns[1].vertical(|ui| {
@@ -64,72 +105,999 @@ fn osc_ui(ui: &mut Ui, osc: &mut Osc, label: &str) {
                         ui.radio_value(&mut osc_wave, Waveform::Square, "Square");
                         ui.radio_value(&mut osc_wave, Waveform::Saw, "Saw");
                         ui.radio_value(&mut osc_wave, Waveform::Triangle, "Triangle");
+                        ui.radio_value(&mut osc_wave, Waveform::Wavetable, "Wavetable");
+                        ui.radio_value(&mut osc_wave, Waveform::WhiteNoise, "White noise");
+                        ui.radio_value(&mut osc_wave, Waveform::PinkNoise, "Pink noise");
+                        ui.radio_value(&mut osc_wave, Waveform::BrownNoise, "Brown noise");
                         osc.waveform.store(osc_wave, Ordering::Release);
+
+                        let mut quality = osc.quality.load(Ordering::Acquire);
+                        egui::ComboBox::new((&label, "quality"), "Quality")
+                            .selected_text(format!("{quality:?}"))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut quality, Quality::Naive, "Naive");
+                                ui.selectable_value(&mut quality, Quality::Standard, "Standard");
+                                ui.selectable_value(&mut quality, Quality::High, "High");
+                            });
+                        osc.quality.store(quality, Ordering::Release);
+                    });
+                });
+
+                ui.add_enabled_ui(active, |ui| {
+                    ui.horizontal(|ui| {
+                        let mut octave = osc.octave.load(Ordering::Acquire);
+                        ui.label("Oct");
+                        ui.add(DragValue::new(&mut octave).range(-3..=3));
+                        osc.octave.store(octave, Ordering::Release);
+
+                        let mut semitone = osc.semitone.load(Ordering::Acquire);
+                        ui.label("Semi");
+                        ui.add(DragValue::new(&mut semitone).range(-12..=12));
+                        osc.semitone.store(semitone, Ordering::Release);
+
+                        let mut cents = osc.cents.load(Ordering::Acquire);
+                        ui.label("Fine");
+                        ui.add(
+                            DragValue::new(&mut cents)
+                                .range(-100.0..=100.0)
+                                .speed(0.5)
+                                .suffix(" ct"),
+                        );
+                        osc.cents.store(cents, Ordering::Release);
+                    });
+
+                    ui.horizontal(|ui| {
+                        let others = OscRef::ALL
+                            .into_iter()
+                            .filter(|other| other.index() != Some(index));
+
+                        let mut sync = osc.sync.load(Ordering::Acquire);
+                        egui::ComboBox::new((&label, "sync"), "Sync")
+                            .selected_text(sync.name())
+                            .show_ui(ui, |ui| {
+                                for other in others.clone() {
+                                    ui.selectable_value(&mut sync, other, other.name());
+                                }
+                            });
+                        osc.sync.store(sync, Ordering::Release);
+
+                        let mut ring = osc.ring.load(Ordering::Acquire);
+                        egui::ComboBox::new((&label, "ring"), "Ring")
+                            .selected_text(ring.name())
+                            .show_ui(ui, |ui| {
+                                for other in others {
+                                    ui.selectable_value(&mut ring, other, other.name());
+                                }
+                            });
+                        osc.ring.store(ring, Ordering::Release);
+                    });
+
+                    egui::CollapsingHeader::new("Unison")
+                        .id_salt((&label, "unison"))
+                        .show(ui, |ui| unison_ui(ui, osc));
+                });
+
+                if osc.waveform.load(Ordering::Acquire) == Waveform::Wavetable {
+                    wavetable_ui(ui, osc, state);
+                }
+            });
+        });
+}
+
+fn unison_ui(ui: &mut Ui, osc: &Osc) {
+    egui::Grid::new("unison").show(ui, |ui| {
+        let mut unison = osc.unison.load(Ordering::Acquire);
+        ui.label("Voices");
+        ui.add(DragValue::new(&mut unison).range(1..=MAX_UNISON));
+        osc.unison.store(unison, Ordering::Release);
+        ui.end_row();
+
+        let mut detune = osc.unison_detune.load(Ordering::Acquire);
+        ui.label("Detune");
+        ui.add(
+            DragValue::new(&mut detune)
+                .range(0.0..=100.0)
+                .speed(0.5)
+                .suffix(" ct"),
+        );
+        osc.unison_detune.store(detune, Ordering::Release);
+        ui.end_row();
+
+        let mut curve = osc.unison_curve.load(Ordering::Acquire);
+        ui.label("Curve");
+        ui.add(DragValue::new(&mut curve).range(0.0..=1.0).speed(0.01));
+        osc.unison_curve.store(curve, Ordering::Release);
+        ui.end_row();
+
+        let mut stereo = osc.unison_stereo.load(Ordering::Acquire);
+        ui.label("Stereo");
+        ui.add(DragValue::new(&mut stereo).range(0.0..=1.0).speed(0.01));
+        osc.unison_stereo.store(stereo, Ordering::Release);
+        ui.end_row();
+
+        let mut random_phase = osc.random_phase.load(Ordering::Acquire);
+        ui.label("Random phase");
+        ui.add(
+            DragValue::new(&mut random_phase)
+                .range(0.0..=1.0)
+                .speed(0.01),
+        );
+        osc.random_phase.store(random_phase, Ordering::Release);
+        ui.end_row();
+    });
+}
+
+fn wavetable_ui(ui: &mut Ui, osc: &Osc, state: &mut OscState) {
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut state.wavetable_path)
+                .hint_text("Wavetable .wav")
+                .desired_width(120.0),
+        );
+        if ui.button("Load").clicked() {
+            match Wavetable::load(Path::new(&state.wavetable_path), wavetable::FRAME_SIZE) {
+                Ok(table) => {
+                    osc.wavetable.lock().unwrap().set(table);
+                    state.wavetable_error = None;
+                }
+                Err(err) => state.wavetable_error = Some(err),
+            }
+        }
+        if ui.button("Basic").clicked() {
+            osc.wavetable.lock().unwrap().set(Wavetable::basic());
+            state.wavetable_error = None;
+        }
+    });
+
+    if let Some(err) = &state.wavetable_error {
+        ui.colored_label(ui.visuals().error_fg_color, err);
+    } else {
+        let frames = osc.wavetable.lock().unwrap().table().frames();
+        ui.label(format!("{frames} frames of {}", wavetable::FRAME_SIZE));
+    }
+}
+
+fn mseg_ui(ui: &mut Ui, params: &Params) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("MSEG");
+
+                // Edit a copy so the audio thread is never kept waiting.
+                let old_shape = *params.mseg.lock().unwrap();
+                let mut shape = old_shape;
+
+                ui.add(MsegEditor::new(&mut shape))
+                    .on_hover_text("Double-click to add or remove points");
+
+                let points = shape.points().len();
+                let point_name = |point: Option<usize>| match point {
+                    Some(index) => format!("Point {}", index + 1),
+                    None => "Off".to_string(),
+                };
+                ui.horizontal(|ui| {
+                    for (label, marker) in [
+                        ("Sustain", &mut shape.sustain),
+                        ("Loop from", &mut shape.loop_start),
+                    ] {
+                        ui.label(label);
+                        egui::ComboBox::from_id_salt(("mseg", label))
+                            .selected_text(point_name(*marker))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(marker, None, point_name(None));
+                                for index in 0..points {
+                                    ui.selectable_value(
+                                        marker,
+                                        Some(index),
+                                        point_name(Some(index)),
+                                    );
+                                }
+                            });
+                    }
+                });
+                ui.label(format!("Length {}", format_time(shape.length())));
+
+                if shape != old_shape {
+                    *params.mseg.lock().unwrap() = shape;
+                }
+            });
+        });
+}
+
+fn lfos_ui(ui: &mut Ui, params: &Params) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("LFOs");
+                    let mut tempo = params.tempo.load(Ordering::Acquire);
+                    ui.add(
+                        DragValue::new(&mut tempo)
+                            .range(20.0..=300.0)
+                            .speed(0.5)
+                            .suffix(" BPM"),
+                    );
+                    params.tempo.store(tempo, Ordering::Release);
+                });
+
+                ui.columns(params.lfos.len(), |columns| {
+                    for (index, (ui, lfo)) in columns.iter_mut().zip(&params.lfos).enumerate() {
+                        lfo_ui(ui, lfo, index);
+                    }
+                });
+            });
+        });
+}
+
+fn lfo_ui(ui: &mut Ui, lfo: &Lfo, index: usize) {
+    let label = format!("LFO {}", index + 1);
+    ui.vertical(|ui| {
+        ui.label(&label);
+
+        let mut shape = lfo.shape.load(Ordering::Acquire);
+        egui::ComboBox::from_id_salt((&label, "shape"))
+            .selected_text(shape.name())
+            .show_ui(ui, |ui| {
+                for option in LfoShape::ALL {
+                    ui.selectable_value(&mut shape, option, option.name());
+                }
+            });
+        lfo.shape.store(shape, Ordering::Release);
+
+        let mut mode = lfo.mode.load(Ordering::Acquire);
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut mode, LfoMode::Voice, "Voice")
+                .on_hover_text("Restarts with every note");
+            ui.radio_value(&mut mode, LfoMode::Global, "Global")
+                .on_hover_text("Runs freely, shared by all notes");
+        });
+        lfo.mode.store(mode, Ordering::Release);
+
+        let mut sync = lfo.sync.load(Ordering::Acquire);
+        ui.checkbox(&mut sync, "Tempo sync");
+        lfo.sync.store(sync, Ordering::Release);
+
+        ui.columns(3, |columns| {
+            columns[0].vertical_centered(|ui| {
+                ui.label("Rate");
+                if sync {
+                    let mut division = lfo.division.load(Ordering::Acquire);
+                    egui::ComboBox::from_id_salt((&label, "division"))
+                        .selected_text(division.name())
+                        .show_ui(ui, |ui| {
+                            for option in Division::ALL {
+                                ui.selectable_value(&mut division, option, option.name());
+                            }
+                        });
+                    lfo.division.store(division, Ordering::Release);
+                } else {
+                    let (min, max) = RATE_RANGE;
+                    let mut rate = lfo.rate.load(Ordering::Acquire);
+                    ui.add(Knob::new(&mut rate, min..=max, 0.005).logarithmic());
+                    ui.label(format!("{rate:.2} Hz"));
+                    lfo.rate.store(rate, Ordering::Release);
+                }
+            });
+            columns[1].vertical_centered(|ui| {
+                let mut phase = lfo.phase.load(Ordering::Acquire);
+                ui.label("Phase");
+                ui.add(Knob::new(&mut phase, 0.0..=0.99, 0.01));
+                lfo.phase.store(phase, Ordering::Release);
+            });
+            columns[2].vertical_centered(|ui| {
+                let mut fade = lfo.fade.load(Ordering::Acquire);
+                ui.label("Fade in");
+                ui.add(Knob::new(&mut fade, 0.0..=ENVELOPE_TIME, 0.005).logarithmic());
+                ui.label(format_time(fade));
+                lfo.fade.store(fade, Ordering::Release);
+            });
+        });
+    });
+}
+
+fn modulation_ui(ui: &mut Ui, params: &Params) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("Modulation");
+
+                egui::Grid::new("modulation").show(ui, |ui| {
+                    for (index, slot) in params.modulation.iter().enumerate() {
+                        let mut source = slot.source.load(Ordering::Acquire);
+                        egui::ComboBox::from_id_salt(("mod source", index))
+                            .selected_text(source.name())
+                            .show_ui(ui, |ui| {
+                                for option in ModSource::ALL {
+                                    ui.selectable_value(&mut source, option, option.name());
+                                }
+                            });
+                        slot.source.store(source, Ordering::Release);
+
+                        let mut destination = slot.destination.load(Ordering::Acquire);
+                        egui::ComboBox::from_id_salt(("mod destination", index))
+                            .selected_text(destination.name())
+                            .show_ui(ui, |ui| {
+                                for option in ModDestination::ALL {
+                                    ui.selectable_value(&mut destination, option, option.name());
+                                }
+                            });
+                        slot.destination.store(destination, Ordering::Release);
+
+                        let mut amount = slot.amount.load(Ordering::Acquire);
+                        ui.add(DragValue::new(&mut amount).range(-1.0..=1.0).speed(0.01));
+                        slot.amount.store(amount, Ordering::Release);
+
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+}
+
+fn fm_ui(ui: &mut Ui, params: &Params) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("FM");
+
+                let mut mode = params.fm.mode.load(Ordering::Acquire);
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut mode, FmMode::Phase, "Phase");
+                    ui.radio_value(&mut mode, FmMode::Frequency, "Frequency");
+                });
+                params.fm.mode.store(mode, Ordering::Release);
+
+                // Rows modulate columns, the diagonal is feedback.
+                egui::Grid::new("fm").show(ui, |ui| {
+                    ui.label("");
+                    for to in 1..=3 {
+                        ui.label(format!("To {to}"));
+                    }
+                    ui.end_row();
+
+                    for (from, row) in params.fm.index.iter().enumerate() {
+                        ui.label(format!("Osc {}", from + 1));
+                        for index in row {
+                            let mut value = index.load(Ordering::Acquire);
+                            ui.add(DragValue::new(&mut value).range(0.0..=8.0).speed(0.01));
+                            index.store(value, Ordering::Release);
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+}
+
+fn envelope_ui(ui: &mut Ui, envelope: &Envelope) {
+    let times = [
+        ("Delay", &envelope.delay),
+        ("Attack", &envelope.attack),
+        ("Hold", &envelope.hold),
+        ("Decay", &envelope.decay),
+        ("Sustain", &envelope.sustain),
+        ("Release", &envelope.release),
+    ];
+    ui.columns(times.len(), |columns| {
+        for (ui, (label, param)) in columns.iter_mut().zip(times) {
+            ui.vertical_centered(|ui| {
+                let mut value = param.load(Ordering::Acquire);
+                ui.label(label);
+                if label == "Sustain" {
+                    ui.add(Knob::new(&mut value, 0.0..=1.0, 0.01));
+                    ui.label(format!("{:.0} %", 100.0 * value));
+                } else {
+                    ui.add(Knob::new(&mut value, 0.0..=ENVELOPE_TIME, 0.005).logarithmic());
+                    ui.label(format_time(value));
+                }
+                param.store(value, Ordering::Release);
+            });
+        }
+    });
+    ui.columns(times.len(), |columns| {
+        for (column, curve) in [
+            (1, &envelope.attack_curve),
+            (3, &envelope.decay_curve),
+            (5, &envelope.release_curve),
+        ] {
+            columns[column].vertical_centered(|ui| {
+                let mut value = curve.load(Ordering::Acquire);
+                ui.label("Curve");
+                ui.add(Knob::new(&mut value, -1.0..=1.0, 0.01));
+                curve.store(value, Ordering::Release);
+            });
+        }
+    });
+
+    let mut looping = envelope.looping.load(Ordering::Acquire);
+    ui.checkbox(&mut looping, "Loop")
+        .on_hover_text("Repeat attack, hold and decay while the key is held");
+    envelope.looping.store(looping, Ordering::Release);
+
+    envelope_graph(ui, envelope);
+}
+
+fn format_time(seconds: f32) -> String {
+    if seconds < 1.0 {
+        format!("{:.0} ms", 1000.0 * seconds)
+    } else {
+        format!("{seconds:.2} s")
+    }
+}
+
+/// Draws the shape of `envelope`, with the sustain shown for a fixed time.
+fn envelope_graph(ui: &mut Ui, envelope: &Envelope) {
+    let delay = envelope.delay.load(Ordering::Acquire);
+    let attack = envelope.attack.load(Ordering::Acquire);
+    let hold = envelope.hold.load(Ordering::Acquire);
+    let decay = envelope.decay.load(Ordering::Acquire);
+    let sustain = envelope.sustain.load(Ordering::Acquire);
+    let release = envelope.release.load(Ordering::Acquire);
+    let attack_curve = envelope.attack_curve.load(Ordering::Acquire);
+    let decay_curve = envelope.decay_curve.load(Ordering::Acquire);
+    let release_curve = envelope.release_curve.load(Ordering::Acquire);
+
+    let sustain_time = (delay + attack + hold + decay + release).max(0.1) / 5.0;
+    let total = delay + attack + hold + decay + sustain_time + release;
+
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 48.0), Sense::hover());
+    if !ui.is_rect_visible(rect) {
+        return;
+    }
+
+    let to_screen = |time: f32, level: f32| {
+        Pos2::new(
+            rect.left() + rect.width() * time / total,
+            rect.bottom() - rect.height() * level,
+        )
+    };
+
+    let steps = 32;
+    let mut points = vec![to_screen(0.0, 0.0), to_screen(delay, 0.0)];
+    for i in 1..=steps {
+        let t = i as f32 / steps as f32;
+        points.push(to_screen(delay + attack * t, curve(t, attack_curve)));
+    }
+    let decay_start = delay + attack + hold;
+    for i in 0..=steps {
+        let t = i as f32 / steps as f32;
+        let level = 1.0 - (1.0 - sustain) * curve(t, decay_curve);
+        points.push(to_screen(decay_start + decay * t, level));
+    }
+    let release_start = decay_start + decay + sustain_time;
+    for i in 0..=steps {
+        let t = i as f32 / steps as f32;
+        let level = sustain * (1.0 - curve(t, release_curve));
+        points.push(to_screen(release_start + release * t, level));
+    }
+
+    let painter = ui.painter();
+    painter.rect_stroke(
+        rect,
+        ui.visuals().widgets.noninteractive.rounding,
+        ui.visuals().widgets.noninteractive.bg_stroke,
+    );
+    painter.add(Shape::line(points, ui.visuals().widgets.inactive.fg_stroke));
+}
+
+fn filter_ui(ui: &mut Ui, params: &Params) {
+    let filter = &params.filter;
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                let mut active = filter.active.load(Ordering::Acquire);
+                ui.checkbox(&mut active, "Filter");
+                filter.active.store(active, Ordering::Release);
+
+                ui.add_enabled_ui(active, |ui| {
+                    let mut kind = filter.kind.load(Ordering::Acquire);
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut kind, FilterType::Svf, "SVF");
+                        ui.radio_value(&mut kind, FilterType::Ladder, "Ladder");
                     });
+                    filter.kind.store(kind, Ordering::Release);
+
+                    match kind {
+                        FilterType::Svf => {
+                            let mut mode = filter.mode.load(Ordering::Acquire);
+                            ui.horizontal(|ui| {
+                                ui.radio_value(&mut mode, FilterMode::LowPass, "LP");
+                                ui.radio_value(&mut mode, FilterMode::HighPass, "HP");
+                                ui.radio_value(&mut mode, FilterMode::BandPass, "BP");
+                                ui.radio_value(&mut mode, FilterMode::Notch, "Notch");
+                            });
+                            filter.mode.store(mode, Ordering::Release);
+                        }
+                        FilterType::Ladder => {
+                            let mut slope = filter.slope.load(Ordering::Acquire);
+                            ui.horizontal(|ui| {
+                                ui.radio_value(&mut slope, Slope::Db12, "12 dB");
+                                ui.radio_value(&mut slope, Slope::Db24, "24 dB");
+                            });
+                            filter.slope.store(slope, Ordering::Release);
+                        }
+                    }
+
+                    let columns = if kind == FilterType::Ladder { 3 } else { 2 };
+                    ui.columns(columns, |columns| {
+                        columns[0].vertical_centered(|ui| {
+                            // The knob moves through the range in octaves.
+                            let (min, max) = CUTOFF_RANGE;
+                            let mut cutoff = filter.cutoff.load(Ordering::Acquire);
+                            let mut position = (cutoff / min).ln() / (max / min).ln();
+                            ui.label("Cutoff");
+                            if ui.add(Knob::new(&mut position, 0.0..=1.0, 0.005)).changed() {
+                                cutoff = min * (max / min).powf(position);
+                            }
+                            ui.add(
+                                DragValue::new(&mut cutoff)
+                                    .range(min..=max)
+                                    .speed(1.0)
+                                    .suffix(" Hz"),
+                            );
+                            filter.cutoff.store(cutoff, Ordering::Release);
+                        });
+                        columns[1].vertical_centered(|ui| {
+                            let mut resonance = filter.resonance.load(Ordering::Acquire);
+                            ui.label("Resonance");
+                            ui.add(Knob::new(&mut resonance, 0.0..=1.0, 0.01));
+                            filter.resonance.store(resonance, Ordering::Release);
+                        });
+                        if let Some(ui) = columns.get_mut(2) {
+                            ui.vertical_centered(|ui| {
+                                let mut drive = filter.drive.load(Ordering::Acquire);
+                                ui.label("Drive");
+                                ui.add(Knob::new(&mut drive, 0.0..=1.0, 0.01));
+                                filter.drive.store(drive, Ordering::Release);
+                            });
+                        }
+                    });
+
+                    ui.columns(3, |columns| {
+                        columns[0].vertical_centered(|ui| {
+                            let mut amount = filter.envelope_amount.load(Ordering::Acquire);
+                            ui.label("Env amount");
+                            ui.add(Knob::new(&mut amount, -1.0..=1.0, 0.01));
+                            filter.envelope_amount.store(amount, Ordering::Release);
+                        });
+                        columns[1].vertical_centered(|ui| {
+                            let mut velocity = filter.velocity.load(Ordering::Acquire);
+                            ui.label("Velocity");
+                            ui.add(Knob::new(&mut velocity, 0.0..=1.0, 0.01));
+                            filter.velocity.store(velocity, Ordering::Release);
+                        });
+                        columns[2].vertical_centered(|ui| {
+                            let mut key_track = filter.key_track.load(Ordering::Acquire);
+                            ui.label("Key track");
+                            ui.add(Knob::new(&mut key_track, 0.0..=1.0, 0.01));
+                            filter.key_track.store(key_track, Ordering::Release);
+                        });
+                    });
+
+                    ui.label("Envelope");
+                    envelope_ui(ui, &filter.envelope);
                 });
             });
         });
 }
 
+fn voices_ui(ui: &mut Ui, params: &Params) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("Voices");
+
+                let mut polyphony = params.polyphony.load(Ordering::Acquire);
+                ui.horizontal(|ui| {
+                    ui.label("Polyphony");
+                    ui.add(DragValue::new(&mut polyphony).range(1..=MAX_VOICES));
+                });
+                params.polyphony.store(polyphony, Ordering::Release);
+
+                let mut policy = params.steal_policy.load(Ordering::Acquire);
+                egui::ComboBox::from_label("Steal")
+                    .selected_text(format!("{policy:?}"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut policy, StealPolicy::Oldest, "Oldest");
+                        ui.selectable_value(&mut policy, StealPolicy::Quietest, "Quietest");
+                        ui.selectable_value(&mut policy, StealPolicy::SameNote, "Same note");
+                        ui.selectable_value(&mut policy, StealPolicy::LowNote, "Low note priority");
+                        ui.selectable_value(
+                            &mut policy,
+                            StealPolicy::HighNote,
+                            "High note priority",
+                        );
+                    });
+                params.steal_policy.store(policy, Ordering::Release);
+            });
+        });
+}
+
+fn velocity_ui(ui: &mut Ui, params: &Params, key_velocity: &mut f32) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("Velocity");
+
+                let mut curve = params.velocity_curve.load(Ordering::Acquire);
+                egui::ComboBox::from_label("Curve")
+                    .selected_text(format!("{curve:?}"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut curve, VelocityCurve::Linear, "Linear");
+                        ui.selectable_value(&mut curve, VelocityCurve::Exponential, "Exponential");
+                        ui.selectable_value(&mut curve, VelocityCurve::Logarithmic, "Logarithmic");
+                        ui.selectable_value(&mut curve, VelocityCurve::Fixed, "Fixed");
+                    });
+                params.velocity_curve.store(curve, Ordering::Release);
+
+                ui.columns(2, |columns| {
+                    columns[0].vertical_centered(|ui| {
+                        let mut amount = params.velocity_amount.load(Ordering::Acquire);
+                        ui.label("Amount");
+                        ui.add(Knob::new(&mut amount, 0.0..=1.0, 0.01));
+                        params.velocity_amount.store(amount, Ordering::Release);
+                    });
+                    columns[1].vertical_centered(|ui| {
+                        ui.label("Keys");
+                        ui.add(Knob::new(key_velocity, 0.0..=1.0, 0.01));
+                    });
+                });
+            });
+        });
+}
+
+fn midi_ui(ui: &mut Ui, keyboard: &mut Keyboard, error: &mut Option<String>) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("MIDI");
+
+                let current = keyboard.midi_backend();
+                let mut backend = current;
+                egui::ComboBox::from_label("Input")
+                    .selected_text(match backend {
+                        None => "None",
+                        Some(MidiBackend::Jack) => "JACK",
+                        Some(MidiBackend::Alsa) => "ALSA",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut backend, None, "None");
+                        ui.selectable_value(&mut backend, Some(MidiBackend::Jack), "JACK");
+                        ui.selectable_value(&mut backend, Some(MidiBackend::Alsa), "ALSA");
+                    });
+                if backend != current {
+                    *error = keyboard.set_midi_backend(backend).err();
+                }
+
+                let mut range = keyboard.params.pitch_bend_range.load(Ordering::Acquire);
+                ui.horizontal(|ui| {
+                    ui.label("Bend range");
+                    ui.add(DragValue::new(&mut range).range(0.0..=24.0).suffix(" st"));
+                });
+                keyboard
+                    .params
+                    .pitch_bend_range
+                    .store(range, Ordering::Release);
+
+                let dropped = keyboard.dropped();
+                if dropped > 0 {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        format!("{dropped} events dropped"),
+                    );
+                }
+
+                if let Some(error) = error {
+                    ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+                }
+            });
+        });
+}
+
+fn audio_ui(ui: &mut Ui, keyboard: &mut Keyboard, devices: &mut Vec<String>) {
+    egui::Frame::default()
+        .stroke(ui.visuals().widgets.noninteractive.bg_stroke)
+        .inner_margin(Margin::same(5.0))
+        .rounding(ui.visuals().widgets.noninteractive.rounding)
+        .show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("Audio");
+
+                let config = keyboard.config();
+                let current_host = config.host.as_deref().and_then(synthesizer::host_by_name);
+                let current_device = config.device.clone();
+
+                let mut host = current_host;
+                egui::ComboBox::from_label("Host")
+                    .selected_text(host.map_or("Automatic", |host| host.name()))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut host, None, "Automatic");
+                        for id in cpal::available_hosts() {
+                            ui.selectable_value(&mut host, Some(id), id.name());
+                        }
+                    });
+
+                let mut device = current_device.clone();
+                ui.add_enabled_ui(host.is_some(), |ui| {
+                    egui::ComboBox::from_label("Device")
+                        .selected_text(device.as_deref().unwrap_or("Default"))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut device, None, "Default");
+                            for name in devices.iter() {
+                                ui.selectable_value(&mut device, Some(name.clone()), name);
+                            }
+                        });
+                });
+
+                if host != current_host {
+                    *devices = host.map(synthesizer::output_devices).unwrap_or_default();
+                    keyboard.set_output(host, None);
+                } else if device != current_device {
+                    keyboard.set_output(host, device);
+                }
+
+                stream_ui(ui, keyboard);
+
+                match keyboard.output() {
+                    Some(output) => {
+                        ui.label(format!(
+                            "Playing on {} ({}), {} Hz, {} ch",
+                            output.host.name(),
+                            output.device,
+                            output.sample_rate,
+                            output.channels,
+                        ));
+                    }
+                    None if keyboard.is_recovering() => {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            "Output lost, reconnecting...",
+                        );
+                    }
+                    None => {
+                        ui.colored_label(ui.visuals().error_fg_color, "No audio output");
+                    }
+                }
+                if let Some(latency) = keyboard.latency() {
+                    ui.label(format!("Latency {:.1} ms", latency * 1000.0));
+                }
+                if let Some(error) = keyboard.audio_error() {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Retry").clicked() {
+                        keyboard.open_output();
+                    }
+                    if ui.button("Refresh devices").clicked() {
+                        *devices = host.map(synthesizer::output_devices).unwrap_or_default();
+                    }
+                });
+            });
+        });
+}
+
+/// How often the audio output is checked for errors while the GUI is idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Buffer sizes offered when the device reports a supported range.
+const BUFFER_SIZES: [u32; 10] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+
+fn stream_ui(ui: &mut Ui, keyboard: &mut Keyboard) {
+    let current = keyboard.config().stream;
+    let caps = keyboard
+        .output()
+        .map(|output| output.capabilities.clone())
+        .unwrap_or_default();
+
+    let mut settings = current;
+    egui::ComboBox::from_label("Sample rate")
+        .selected_text(
+            settings
+                .sample_rate
+                .map_or("Maximum".to_string(), |r| format!("{r} Hz")),
+        )
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut settings.sample_rate, None, "Maximum");
+            for rate in caps.sample_rates.iter() {
+                ui.selectable_value(&mut settings.sample_rate, Some(*rate), format!("{rate} Hz"));
+            }
+        });
+
+    egui::ComboBox::from_label("Buffer")
+        .selected_text(
+            settings
+                .buffer_size
+                .map_or("Default".to_string(), |n| format!("{n} frames")),
+        )
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut settings.buffer_size, None, "Default");
+            if let Some((min, max)) = caps.buffer_sizes {
+                for size in BUFFER_SIZES
+                    .into_iter()
+                    .filter(|size| (min..=max).contains(size))
+                {
+                    ui.selectable_value(
+                        &mut settings.buffer_size,
+                        Some(size),
+                        format!("{size} frames"),
+                    );
+                }
+            }
+        });
+
+    egui::ComboBox::from_label("Channels")
+        .selected_text(
+            settings
+                .channels
+                .map_or("Automatic".to_string(), |n| n.to_string()),
+        )
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut settings.channels, None, "Automatic");
+            for channels in caps.channels.iter() {
+                let label = match channels {
+                    1 => "Mono".to_string(),
+                    2 => "Stereo".to_string(),
+                    n => n.to_string(),
+                };
+                ui.selectable_value(&mut settings.channels, Some(*channels), label);
+            }
+        });
+
+    if settings != current {
+        keyboard.set_stream_settings(settings);
+    }
+}
+
+/// Computer keyboard layout, starting at C of the selected octave. The bottom
+/// row plays the first octave and the top row continues into the next one.
+const KEY_MAP: [egui::Key; 29] = [
+    egui::Key::Z,
+    egui::Key::S,
+    egui::Key::X,
+    egui::Key::D,
+    egui::Key::C,
+    egui::Key::V,
+    egui::Key::G,
+    egui::Key::B,
+    egui::Key::H,
+    egui::Key::N,
+    egui::Key::J,
+    egui::Key::M,
+    egui::Key::Q,
+    egui::Key::Num2,
+    egui::Key::W,
+    egui::Key::Num3,
+    egui::Key::E,
+    egui::Key::R,
+    egui::Key::Num5,
+    egui::Key::T,
+    egui::Key::Num6,
+    egui::Key::Y,
+    egui::Key::Num7,
+    egui::Key::U,
+    egui::Key::I,
+    egui::Key::Num9,
+    egui::Key::O,
+    egui::Key::Num0,
+    egui::Key::P,
+];
+
 pub struct VirtSynth {
     keyboard: Keyboard,
+    /// Octave offset relative to C4 for the computer keyboard.
+    octave: i32,
+    held: [Option<Key>; KEY_MAP.len()],
+    /// Velocity of notes played on the computer keyboard.
+    velocity: f32,
+    midi_error: Option<String>,
+    /// Output devices of the selected audio host.
+    devices: Vec<String>,
+    oscs: [OscState; 3],
 }
 
 impl VirtSynth {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         cc.egui_ctx.set_theme(Theme::Light);
+        let keyboard = Keyboard::new();
+        let devices = keyboard
+            .config()
+            .host
+            .as_deref()
+            .and_then(synthesizer::host_by_name)
+            .map(synthesizer::output_devices)
+            .unwrap_or_default();
         Self {
-            keyboard: Keyboard::new(),
+            keyboard,
+            octave: 0,
+            held: [None; KEY_MAP.len()],
+            velocity: 1.0,
+            midi_error: None,
+            devices,
+            oscs: Default::default(),
         }
     }
 
-    fn get_active_keys(&self, ctx: &egui::Context) -> KeyBitflags {
-        let mut active_keys = KeyBitflags(0, 1);
-        if ctx.input(|i| i.key_down(egui::Key::Z)) {
-            active_keys.0 |= Key::C4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::S)) {
-            active_keys.0 |= Key::CS.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::X)) {
-            active_keys.0 |= Key::D4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::D)) {
-            active_keys.0 |= Key::DS.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::C)) {
-            active_keys.0 |= Key::E4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::V)) {
-            active_keys.0 |= Key::F4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::G)) {
-            active_keys.0 |= Key::FS.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::B)) {
-            active_keys.0 |= Key::G4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::H)) {
-            active_keys.0 |= Key::GS.bitflag();
+    /// Stops the notes played from the computer keyboard.
+    fn release_held(&mut self) {
+        for key in self.held.iter_mut().filter_map(Option::take) {
+            self.keyboard.note_off(key);
         }
-        if ctx.input(|i| i.key_down(egui::Key::N)) {
-            active_keys.0 |= Key::A4.bitflag();
-        }
-        if ctx.input(|i| i.key_down(egui::Key::J)) {
-            active_keys.0 |= Key::AS.bitflag();
+    }
+
+    fn handle_key_events(&mut self, ctx: &egui::Context) {
+        // Typing into a text field or drag value does not play notes. Held
+        // notes are released, their key up goes to the field.
+        if ctx.wants_keyboard_input() {
+            self.release_held();
+            return;
         }
-        if ctx.input(|i| i.key_down(egui::Key::M)) {
-            active_keys.0 |= Key::B4.bitflag();
+
+        let events = ctx.input(|i| i.events.clone());
+        for event in events {
+            match event {
+                egui::Event::Key {
+                    key,
+                    pressed,
+                    repeat: false,
+                    ..
+                } => {
+                    let Some(slot) = KEY_MAP.iter().position(|k| *k == key) else {
+                        continue;
+                    };
+
+                    if !pressed {
+                        if let Some(key) = self.held[slot].take() {
+                            self.keyboard.note_off(key);
+                        }
+                    } else if self.held[slot].is_none() {
+                        // The key is remembered so the note off matches even
+                        // if the octave changes while it is held.
+                        if let Some(key) = Key::C4.offset(self.octave * 12 + slot as i32) {
+                            self.keyboard.note_on(key, self.velocity);
+                            self.held[slot] = Some(key);
+                        }
+                    }
+                }
+                egui::Event::WindowFocused(false) => self.release_held(),
+                _ => {}
+            }
         }
-        active_keys
     }
 }
 
 impl eframe::App for VirtSynth {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
-            let active_keys = self.get_active_keys(ctx);
-            self.keyboard.set_active_keys(active_keys.0);
+            self.handle_key_events(ctx);
+
+            self.keyboard.poll();
+            // Keep polling for stream errors when there is no input.
+            ctx.request_repaint_after(POLL_INTERVAL);
 
             ui.horizontal_wrapped(|ui| {
                 egui::Frame::default()
@@ -142,28 +1110,38 @@ impl eframe::App for VirtSynth {
                             ui.columns(1, |columns| {
                                 columns[0].vertical_centered(|ui| {
                                     ui.label("Volume");
-                                    let mut gain = self.keyboard.gain.load(Ordering::Acquire);
+                                    let mut gain =
+                                        self.keyboard.params.gain.load(Ordering::Acquire);
                                     ui.add(Knob::new(&mut gain, 0.0..=1.0, 0.01));
                                     let mut gain_perc = (gain * 100.0) as u8;
                                     ui.add(DragValue::new(&mut gain_perc).speed(1).suffix("%"));
                                     gain = gain_perc as f32 / 100.0;
-                                    self.keyboard.gain.store(gain, Ordering::Release);
+                                    self.keyboard.params.gain.store(gain, Ordering::Release);
                                 });
                             });
+                            ui.horizontal(|ui| {
+                                ui.label("Octave");
+                                ui.add(DragValue::new(&mut self.octave).range(-4..=4));
+                            });
                         });
                     });
 
+                voices_ui(ui, &self.keyboard.params);
+                velocity_ui(ui, &self.keyboard.params, &mut self.velocity);
+                midi_ui(ui, &mut self.keyboard, &mut self.midi_error);
+                audio_ui(ui, &mut self.keyboard, &mut self.devices);
+
                 ui.end_row();
 
                 ui.columns(3, |colums| {
                     colums[0].horizontal(|ui| {
-                        osc_ui(ui, &mut self.keyboard.osc1, "Oscillator 1");
+                        osc_ui(ui, &mut self.keyboard.params.osc1, 0, &mut self.oscs[0]);
                     });
                     colums[1].horizontal(|ui| {
-                        osc_ui(ui, &mut self.keyboard.osc2, "Oscillator 2");
+                        osc_ui(ui, &mut self.keyboard.params.osc2, 1, &mut self.oscs[1]);
                     });
                     colums[2].horizontal(|ui| {
-                        osc_ui(ui, &mut self.keyboard.osc3, "Oscillator 3");
+                        osc_ui(ui, &mut self.keyboard.params.osc3, 2, &mut self.oscs[2]);
                     });
                 });
 
@@ -176,34 +1154,15 @@ impl eframe::App for VirtSynth {
                     .show(ui, |ui| {
                         ui.vertical(|ui| {
                             ui.label("Envelope");
-                            ui.columns(4, |columns| {
-                                columns[0].vertical_centered(|ui| {
-                                    let mut attack = self.keyboard.attack.load(Ordering::Acquire);
-                                    ui.label("Attack");
-                                    ui.add(Knob::new(&mut attack, 0.0..=1.0, 0.01));
-                                    self.keyboard.attack.store(attack, Ordering::Release);
-                                });
-                                columns[1].vertical_centered(|ui| {
-                                    let mut decay = self.keyboard.decay.load(Ordering::Acquire);
-                                    ui.label("Decay");
-                                    ui.add(Knob::new(&mut decay, 0.0..=1.0, 0.01));
-                                    self.keyboard.decay.store(decay, Ordering::Release);
-                                });
-                                columns[2].vertical_centered(|ui| {
-                                    let mut sustain = self.keyboard.sustain.load(Ordering::Acquire);
-                                    ui.label("Sustain");
-                                    ui.add(Knob::new(&mut sustain, 0.0..=1.0, 0.01));
-                                    self.keyboard.sustain.store(sustain, Ordering::Release);
-                                });
-                                columns[3].vertical_centered(|ui| {
-                                    let mut release = self.keyboard.release.load(Ordering::Acquire);
-                                    ui.label("Release");
-                                    ui.add(Knob::new(&mut release, 0.0..=1.0, 0.01));
-                                    self.keyboard.release.store(release, Ordering::Release);
-                                });
-                            });
+                            envelope_ui(ui, &self.keyboard.params.envelope);
                         });
                     });
+
+                filter_ui(ui, &self.keyboard.params);
+                mseg_ui(ui, &self.keyboard.params);
+                lfos_ui(ui, &self.keyboard.params);
+                modulation_ui(ui, &self.keyboard.params);
+                fm_ui(ui, &self.keyboard.params);
             });
         });
     }
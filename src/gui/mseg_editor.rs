@@ -0,0 +1,211 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use eframe::egui::{Align2, FontId, Pos2, Rect, Response, Sense, Shape, Stroke, Ui, Vec2, Widget};
+
+use crate::mseg::{self, Point};
+
+/// How close to a handle, in points, the pointer has to be to grab it.
+const GRAB_RADIUS: f32 = 8.0;
+
+/// Handle being dragged, with the seconds the width showed when the drag
+/// started so the scale does not shift under the pointer.
+#[derive(Clone, Copy)]
+enum Drag {
+    Point(usize, f32),
+    Curve(usize),
+}
+
+/// Breakpoint editor for an MSEG shape. Drag points to move them, drag the
+/// handle in the middle of a segment to bend it and double-click to add or
+/// remove points.
+#[must_use = "You should put this widget in a ui with `ui.add(widget);`"]
+pub struct MsegEditor<'a> {
+    shape: &'a mut mseg::Shape,
+}
+
+impl<'a> MsegEditor<'a> {
+    pub fn new(shape: &'a mut mseg::Shape) -> Self {
+        Self { shape }
+    }
+
+    /// Seconds across the full width, with room to drag the last point
+    /// further out.
+    fn view(&self) -> f32 {
+        self.shape.length().max(0.1) * 1.25
+    }
+
+    /// Handle under `pos`, points before curve handles.
+    fn hit(&self, pos: Pos2, to_screen: impl Fn(f32, f32) -> Pos2 + Copy) -> Option<Drag> {
+        let points = self.shape.points();
+        let near = |handle: Pos2| handle.distance(pos) < GRAB_RADIUS;
+
+        if let Some(index) = points
+            .iter()
+            .position(|point| near(to_screen(point.time, point.level)))
+        {
+            return Some(Drag::Point(index, self.view()));
+        }
+        (1..points.len())
+            .find(|&index| near(curve_handle(self.shape, index, to_screen)))
+            .map(Drag::Curve)
+    }
+}
+
+impl Widget for MsegEditor<'_> {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let id = ui.next_auto_id();
+        let old_shape = *self.shape;
+
+        let size = Vec2::new(ui.available_width(), 120.0);
+        let (rect, mut response) = ui.allocate_at_least(size, Sense::click_and_drag());
+
+        let view = self.view();
+        let to_screen = move |time: f32, level: f32| {
+            Pos2::new(
+                rect.left() + rect.width() * time / view,
+                rect.bottom() - rect.height() * level,
+            )
+        };
+        let from_screen = |pos: Pos2, view: f32| {
+            (
+                (pos.x - rect.left()) / rect.width() * view,
+                (rect.bottom() - pos.y) / rect.height(),
+            )
+        };
+
+        if response.drag_started() {
+            let drag = response
+                .interact_pointer_pos()
+                .and_then(|pos| self.hit(pos, to_screen));
+            ui.data_mut(|data| match drag {
+                Some(drag) => data.insert_temp(id, drag),
+                None => data.remove::<Drag>(id),
+            });
+        }
+
+        if response.dragged() {
+            let drag = ui.data_mut(|data| data.get_temp::<Drag>(id));
+            match (drag, response.interact_pointer_pos()) {
+                (Some(Drag::Point(index, view)), Some(pos)) => {
+                    let (time, level) = from_screen(pos, view);
+                    self.shape.set(index, time, level);
+                }
+                (Some(Drag::Curve(index)), _) => {
+                    // Dragging up bulges the segment up whichever way it
+                    // goes.
+                    let points = self.shape.points();
+                    let rising = points[index].level >= points[index - 1].level;
+                    let delta = 0.01 * response.drag_delta().y;
+                    let curve = points[index].curve + if rising { delta } else { -delta };
+                    self.shape.set_curve(index, curve);
+                }
+                _ => {}
+            }
+        }
+
+        if response.drag_stopped() {
+            ui.data_mut(|data| data.remove::<Drag>(id));
+        }
+
+        if response.double_clicked() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                match self.hit(pos, to_screen) {
+                    Some(Drag::Point(index, _)) => self.shape.remove(index),
+                    Some(Drag::Curve(_)) => {}
+                    None => {
+                        let (time, level) = from_screen(pos, view);
+                        self.shape.insert(Point {
+                            time,
+                            level: level.clamp(0.0, 1.0),
+                            curve: 0.0,
+                        });
+                    }
+                }
+            }
+        }
+
+        if ui.is_rect_visible(rect) {
+            paint(ui, rect, self.shape, to_screen);
+        }
+
+        response.changed = *self.shape != old_shape;
+        response
+    }
+}
+
+fn paint(ui: &Ui, rect: Rect, shape: &mseg::Shape, to_screen: impl Fn(f32, f32) -> Pos2 + Copy) {
+    let visuals = ui.visuals();
+    let painter = ui.painter_at(rect);
+    let stroke = visuals.widgets.inactive.fg_stroke;
+    let weak = Stroke::new(1.0, visuals.weak_text_color());
+
+    painter.rect_stroke(
+        rect,
+        visuals.widgets.noninteractive.rounding,
+        visuals.widgets.noninteractive.bg_stroke,
+    );
+
+    let points = shape.points();
+    for (marker, label) in [(shape.loop_start, "L"), (shape.sustain, "S")] {
+        if let Some(point) = marker.and_then(|index| points.get(index)) {
+            let x = to_screen(point.time, 0.0).x;
+            painter.vline(x, rect.y_range(), weak);
+            painter.text(
+                Pos2::new(x + 2.0, rect.top()),
+                Align2::LEFT_TOP,
+                label,
+                FontId::default(),
+                visuals.weak_text_color(),
+            );
+        }
+    }
+
+    let steps = 32;
+    let mut line = Vec::with_capacity(points.len() * steps);
+    line.push(to_screen(points[0].time, points[0].level));
+    for index in 1..points.len() {
+        let (from, to) = (points[index - 1], points[index]);
+        for i in 1..=steps {
+            let t = i as f32 / steps as f32;
+            let time = from.time + (to.time - from.time) * t;
+            line.push(to_screen(time, shape.segment(index, from.level, t)));
+        }
+    }
+    painter.add(Shape::line(line, stroke));
+
+    for index in 1..points.len() {
+        painter.circle_stroke(curve_handle(shape, index, to_screen), 3.0, weak);
+    }
+    for point in points {
+        painter.circle_filled(
+            to_screen(point.time, point.level),
+            4.0,
+            visuals.widgets.active.fg_stroke.color,
+        );
+    }
+}
+
+/// Where the handle bending the segment ending at point `index` is drawn.
+fn curve_handle(shape: &mseg::Shape, index: usize, to_screen: impl Fn(f32, f32) -> Pos2) -> Pos2 {
+    let points = shape.points();
+    let (from, to) = (points[index - 1], points[index]);
+    to_screen(
+        0.5 * (from.time + to.time),
+        shape.segment(index, from.level, 0.5),
+    )
+}
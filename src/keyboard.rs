@@ -15,248 +15,274 @@
  * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::{
-    atomic::{AtomicBool, AtomicUsize, Ordering},
-    Arc,
-};
+use std::str::FromStr;
+
+#[cfg(all(feature = "audio", feature = "midi"))]
+use std::sync::{atomic::AtomicU64, Arc};
 
+#[cfg(all(feature = "audio", feature = "midi"))]
 use crate::{
-    atomicf::{AtomicF32, AtomicWaveform},
-    synthesizer::Synthesizer,
-    waveform::Waveform,
+    config::Config,
+    event::{self, Event, EventSender, SharedQueueSlot},
+    midi::{MidiBackend, MidiInput, MidiQueues},
+    params::Params,
+    synthesizer::{self, OutputInfo, StreamSettings, Synthesizer},
 };
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
-pub enum Key {
-    C4 = 40,
-    CS = 41,
-    D4 = 42,
-    DS = 43,
-    E4 = 44,
-    F4 = 45,
-    FS = 46,
-    G4 = 47,
-    GS = 48,
-    A4 = 49,
-    AS = 50,
-    B4 = 51,
-}
+/// A MIDI note number in the range `0..=127`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct Key(u8);
 
 impl Key {
-    pub fn freq(self) -> f32 {
-        2.0f32.powf((self as i32 - 49) as f32 / 12.0) * 440.0
-    }
+    /// Number of addressable keys.
+    pub const COUNT: usize = 128;
+
+    pub const C4: Key = Key(60);
+    pub const A4: Key = Key(69);
 
-    pub fn bitflag(self) -> usize {
-        match self {
-            Key::C4 => 0b000000000001,
-            Key::CS => 0b000000000010,
-            Key::D4 => 0b000000000100,
-            Key::DS => 0b000000001000,
-            Key::E4 => 0b000000010000,
-            Key::F4 => 0b000000100000,
-            Key::FS => 0b000001000000,
-            Key::G4 => 0b000010000000,
-            Key::GS => 0b000100000000,
-            Key::A4 => 0b001000000000,
-            Key::AS => 0b010000000000,
-            Key::B4 => 0b100000000000,
+    pub fn new(note: u8) -> Option<Self> {
+        if (note as usize) < Self::COUNT {
+            Some(Self(note))
+        } else {
+            None
         }
     }
 
-    fn from_bitflag(bitflag: usize) -> Self {
-        match bitflag {
-            0b000000000001 => Key::C4,
-            0b000000000010 => Key::CS,
-            0b000000000100 => Key::D4,
-            0b000000001000 => Key::DS,
-            0b000000010000 => Key::E4,
-            0b000000100000 => Key::F4,
-            0b000001000000 => Key::FS,
-            0b000010000000 => Key::G4,
-            0b000100000000 => Key::GS,
-            0b001000000000 => Key::A4,
-            0b010000000000 => Key::AS,
-            0b100000000000 => Key::B4,
-            _ => unreachable!(),
-        }
+    #[inline(always)]
+    pub fn note(self) -> u8 {
+        self.0
     }
 
-    pub fn from_zero_index(index: usize) -> Self {
-        match index {
-            0 => Key::C4,
-            1 => Key::CS,
-            2 => Key::D4,
-            3 => Key::DS,
-            4 => Key::E4,
-            5 => Key::F4,
-            6 => Key::FS,
-            7 => Key::G4,
-            8 => Key::GS,
-            9 => Key::A4,
-            10 => Key::AS,
-            11 => Key::B4,
-            _ => unreachable!(),
-        }
+    #[inline(always)]
+    pub fn freq(self) -> f32 {
+        2.0f32.powf((self.0 as i32 - Self::A4.0 as i32) as f32 / 12.0) * 440.0
     }
-}
 
-#[derive(Copy, Clone)]
-pub struct KeyBitflags(pub usize, pub usize);
-
-impl KeyBitflags {
-    pub fn all() -> Self {
-        Self(
-            Key::C4.bitflag()
-                | Key::CS.bitflag()
-                | Key::D4.bitflag()
-                | Key::DS.bitflag()
-                | Key::E4.bitflag()
-                | Key::F4.bitflag()
-                | Key::FS.bitflag()
-                | Key::G4.bitflag()
-                | Key::GS.bitflag()
-                | Key::A4.bitflag()
-                | Key::AS.bitflag()
-                | Key::B4.bitflag(),
-            1,
-        )
+    /// Returns the key `semitones` away from this one, if it is in range.
+    pub fn offset(self, semitones: i32) -> Option<Self> {
+        let note = self.0 as i32 + semitones;
+        if (0..Self::COUNT as i32).contains(&note) {
+            Some(Self(note as u8))
+        } else {
+            None
+        }
     }
 }
 
-impl Iterator for KeyBitflags {
-    type Item = Key;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.1 > Key::B4.bitflag() {
-            return None;
-        }
+impl FromStr for Key {
+    type Err = String;
 
-        while self.1 <= Key::B4.bitflag() {
-            if (self.0 & self.1) > 0 {
-                let key = Key::from_bitflag(self.1);
-                self.1 <<= 1;
+    /// Parses a note number (`60`) or a note name with octave (`C4`, `F#3`,
+    /// `Bb-1`), where C4 is middle C.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid note \"{s}\"");
 
-                return Some(key);
-            }
-            self.1 <<= 1;
+        if let Ok(note) = s.parse::<u8>() {
+            return Key::new(note).ok_or_else(invalid);
         }
 
-        None
+        let mut chars = s.chars();
+        let semitone = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(invalid()),
+        };
+        let rest = chars.as_str();
+        let (semitone, octave) = if let Some(octave) = rest.strip_prefix('#') {
+            (semitone + 1, octave)
+        } else if let Some(octave) = rest.strip_prefix('b') {
+            (semitone - 1, octave)
+        } else {
+            (semitone, rest)
+        };
+        let octave: i32 = octave.parse().map_err(|_| invalid())?;
+
+        Key::C4
+            .offset((octave - 4) * 12 + semitone)
+            .ok_or_else(invalid)
     }
 }
 
-pub struct Osc {
-    pub active: Arc<AtomicBool>,
-    pub waveform: Arc<AtomicWaveform>,
-    pub gain: Arc<AtomicF32>,
+/// The live instrument: the engine playing through an audio output, fed by
+/// the GUI and MIDI input.
+#[cfg(all(feature = "audio", feature = "midi"))]
+pub struct Keyboard {
+    pub params: Params,
+    events: EventSender,
+    /// Notes from [`Keyboard::note_on`] and [`Keyboard::note_off`] dropped
+    /// because the queue was full.
+    dropped: usize,
+    midi_queues: MidiQueues,
+    midi: Option<MidiInput>,
+    synth: Synthesizer,
+    config: Config,
+    audio_error: Option<String>,
 }
 
-impl Osc {
-    pub fn new(active: bool, waveform: Waveform, gain: f32) -> (Self, Self) {
-        let active = Arc::new(AtomicBool::new(active));
-        let waveform = Arc::new(AtomicWaveform::new(waveform));
-        let gain = Arc::new(AtomicF32::new(gain));
-
-        (
-            Self {
-                active: Arc::clone(&active),
-                waveform: Arc::clone(&waveform),
-                gain: Arc::clone(&gain),
-            },
-            Self {
-                active,
-                waveform,
-                gain,
-            },
-        )
+#[cfg(all(feature = "audio", feature = "midi"))]
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct Keyboard {
-    pub gain: Arc<AtomicF32>,
-    active_keys: Arc<AtomicUsize>,
-    pub attack: Arc<AtomicF32>,
-    pub decay: Arc<AtomicF32>,
-    pub sustain: Arc<AtomicF32>,
-    pub release: Arc<AtomicF32>,
-    _synth: Synthesizer,
-    pub osc1: Osc,
-    pub osc2: Osc,
-    pub osc3: Osc,
-    // pub osc_active: Arc<AtomicBool>,
-    // pub osc_waveform: Arc<AtomicWaveform>,
-    // pub osc_scale: Arc<AtomicF32>,
-}
-
+#[cfg(all(feature = "audio", feature = "midi"))]
 impl Keyboard {
     pub fn new() -> Self {
-        let active_keys = Arc::new(AtomicUsize::new(0));
-        let active_keys_clone = Arc::clone(&active_keys);
+        let params = Params::default();
+        let clock = Arc::new(AtomicU64::new(0));
+        let (events, consumer) = event::queue(Arc::clone(&clock));
+        let (alsa_events, alsa_consumer) = event::queue(Arc::clone(&clock));
 
-        let gain = Arc::new(AtomicF32::new(0.5));
-        let gain_clone = Arc::clone(&gain);
+        let synth = Synthesizer::new(
+            params.clone(),
+            vec![consumer, alsa_consumer],
+            Arc::clone(&clock),
+        );
+        // JACK gets a new queue every time its port opens.
+        let jack_queue = SharedQueueSlot::default();
+        synth.share_queue(Arc::clone(&jack_queue));
 
-        let attack = Arc::new(AtomicF32::new(0.1));
-        let attack_clone = Arc::clone(&attack);
+        let mut keyboard = Self {
+            params,
+            events,
+            dropped: 0,
+            midi_queues: MidiQueues::new(alsa_events, jack_queue, clock),
+            midi: None,
+            synth,
+            config: Config::load(),
+            audio_error: None,
+        };
+        keyboard.open_output();
+        keyboard
+    }
 
-        let decay = Arc::new(AtomicF32::new(0.0));
-        let decay_clone = Arc::clone(&decay);
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
-        let sustain = Arc::new(AtomicF32::new(1.0));
-        let sustain_clone = Arc::clone(&sustain);
+    /// The running output stream, if any.
+    pub fn output(&self) -> Option<&OutputInfo> {
+        self.synth.output()
+    }
 
-        let release = Arc::new(AtomicF32::new(0.1));
-        let release_clone = Arc::clone(&release);
+    /// Output latency of one buffer in seconds.
+    pub fn latency(&self) -> Option<f32> {
+        self.synth.latency()
+    }
 
-        // TODO: Make arcs or something
-        let (osc1_clone, osc1) = Osc::new(true, Waveform::Sin, 1.0);
-        let (osc2_clone, osc2) = Osc::new(false, Waveform::Sin, 1.0);
-        let (osc3_clone, osc3) = Osc::new(false, Waveform::Sin, 1.0);
+    /// Why the audio output could not be opened. Set when no output is
+    /// playing, or when a fallback output is used instead of the selected
+    /// one.
+    pub fn audio_error(&self) -> Option<&str> {
+        self.audio_error.as_deref()
+    }
 
-        // let osc_active = Arc::new(AtomicBool::new(false));
-        // let osc_active_clone = Arc::clone(&osc_active);
+    /// Selects and saves the audio output. `None` picks automatically.
+    pub fn set_output(&mut self, host: Option<cpal::HostId>, device: Option<String>) {
+        self.config.host = host.map(|host| host.name().to_string());
+        self.config.device = device;
+        self.save_and_reopen();
+    }
 
-        // let osc_waveform = Arc::new(AtomicWaveform::new(Waveform::Sin));
-        // let osc_waveform_clone = Arc::clone(&osc_waveform);
+    /// Selects and saves the stream configuration, reopening the output.
+    pub fn set_stream_settings(&mut self, settings: StreamSettings) {
+        self.config.stream = settings;
+        self.save_and_reopen();
+    }
 
-        // let osc_scale = Arc::new(AtomicF32::new(1.0));
-        // let osc_scale_clone = Arc::clone(&osc_scale);
+    fn save_and_reopen(&mut self) {
+        // Failing to save only loses the choice for the next run.
+        let _ = self.config.save();
+        self.open_output();
+    }
 
-        let synth = Synthesizer::new(
-            gain_clone,
-            active_keys_clone,
-            attack_clone,
-            decay_clone,
-            sustain_clone,
-            release_clone,
-            osc1_clone,
-            osc2_clone,
-            osc3_clone,
-            // osc_active_clone,
-            // osc_waveform_clone,
-            // osc_scale_clone,
-        );
+    /// Picks up errors from the running stream and reopens a lost output.
+    /// Has to be called regularly.
+    pub fn poll(&mut self) {
+        let recovering = self.synth.is_recovering();
+        if let Some(err) = self.synth.poll() {
+            self.audio_error = Some(err.to_string());
+        } else if recovering && !self.synth.is_recovering() {
+            self.audio_error = None;
+        }
+    }
 
-        Self {
-            active_keys,
-            gain,
-            _synth: synth,
-            attack,
-            decay,
-            sustain,
-            release,
-            osc1,
-            osc2,
-            osc3,
-            // osc_active,
-            // osc_waveform,
-            // osc_scale,
+    /// True while a lost output is being reopened.
+    pub fn is_recovering(&self) -> bool {
+        self.synth.is_recovering()
+    }
+
+    /// (Re)opens the configured audio output.
+    pub fn open_output(&mut self) {
+        let host = self
+            .config
+            .host
+            .as_deref()
+            .and_then(synthesizer::host_by_name);
+        let device = self.config.device.as_deref();
+        self.audio_error = self
+            .synth
+            .open(host, device, self.config.stream)
+            .err()
+            .map(|err| err.to_string());
+
+        if self.audio_error.is_none() && host.is_some() {
+            if let Some(output) = self.synth.output() {
+                if Some(output.host) != host || device.is_some_and(|device| device != output.device)
+                {
+                    self.audio_error = Some(format!(
+                        "Selected output unavailable, using {} instead",
+                        output.host.name()
+                    ));
+                }
+            }
         }
     }
 
-    #[inline(always)]
-    pub fn set_active_keys(&mut self, active_keys: usize) {
-        self.active_keys.store(active_keys, Ordering::Release);
+    pub fn midi_backend(&self) -> Option<MidiBackend> {
+        self.midi.as_ref().map(MidiInput::backend)
+    }
+
+    /// Switches MIDI input to `backend`, or closes it if `None`.
+    pub fn set_midi_backend(&mut self, backend: Option<MidiBackend>) -> Result<(), String> {
+        if backend == self.midi_backend() {
+            return Ok(());
+        }
+
+        // The old port has to be gone before JACK or ALSA will accept a new
+        // client with the same name.
+        self.midi = None;
+        if let Some(backend) = backend {
+            self.midi = Some(MidiInput::open(backend, &self.midi_queues)?);
+        }
+
+        Ok(())
+    }
+
+    /// Events from the GUI and MIDI input dropped because the engine fell
+    /// behind. A dropped note-off leaves the note stuck until it is played
+    /// again.
+    pub fn dropped(&self) -> usize {
+        self.dropped + self.midi_queues.dropped()
+    }
+
+    pub fn note_on(&mut self, key: Key, velocity: f32) {
+        self.send(Event::NoteOn { key, velocity });
+    }
+
+    pub fn note_off(&mut self, key: Key) {
+        self.send(Event::NoteOff { key });
+    }
+
+    fn send(&mut self, event: Event) {
+        if !self.events.send(event) {
+            self.dropped += 1;
+        }
     }
 }
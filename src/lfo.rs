@@ -0,0 +1,393 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Low-frequency oscillators for modulation.
+
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    atomicf::{AtomicDivision, AtomicF32, AtomicLfoMode, AtomicLfoShape},
+    noise::Rng,
+    params,
+};
+
+/// Number of LFOs.
+pub const LFOS: usize = 2;
+
+/// Slowest and fastest free-running rate in Hz.
+pub const RATE_RANGE: (f32, f32) = (0.01, 50.0);
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LfoShape {
+    Sine = 1,
+    Triangle = 2,
+    Saw = 3,
+    Square = 4,
+    /// A new random value every cycle.
+    SampleHold = 5,
+    /// Glides to a new random value every cycle.
+    SmoothRandom = 6,
+}
+
+impl From<i32> for LfoShape {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Sine,
+            2 => Self::Triangle,
+            3 => Self::Saw,
+            4 => Self::Square,
+            5 => Self::SampleHold,
+            6 => Self::SmoothRandom,
+            _ => panic!("Invalid LFO shape integer"),
+        }
+    }
+}
+
+impl LfoShape {
+    pub const ALL: [Self; 6] = [
+        Self::Sine,
+        Self::Triangle,
+        Self::Saw,
+        Self::Square,
+        Self::SampleHold,
+        Self::SmoothRandom,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sine => "Sine",
+            Self::Triangle => "Triangle",
+            Self::Saw => "Saw",
+            Self::Square => "Square",
+            Self::SampleHold => "S&H",
+            Self::SmoothRandom => "Smooth random",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LfoMode {
+    /// Every voice has its own LFO, restarted by its note.
+    Voice = 1,
+    /// One free-running LFO shared by all voices.
+    Global = 2,
+}
+
+impl From<i32> for LfoMode {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Voice,
+            2 => Self::Global,
+            _ => panic!("Invalid LFO mode integer"),
+        }
+    }
+}
+
+/// Note length one cycle takes when synced to the tempo.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Division {
+    FourBars = 1,
+    TwoBars = 2,
+    Bar = 3,
+    Half = 4,
+    Quarter = 5,
+    DottedQuarter = 6,
+    QuarterTriplet = 7,
+    Eighth = 8,
+    DottedEighth = 9,
+    EighthTriplet = 10,
+    Sixteenth = 11,
+    SixteenthTriplet = 12,
+    ThirtySecond = 13,
+}
+
+impl From<i32> for Division {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::FourBars,
+            2 => Self::TwoBars,
+            3 => Self::Bar,
+            4 => Self::Half,
+            5 => Self::Quarter,
+            6 => Self::DottedQuarter,
+            7 => Self::QuarterTriplet,
+            8 => Self::Eighth,
+            9 => Self::DottedEighth,
+            10 => Self::EighthTriplet,
+            11 => Self::Sixteenth,
+            12 => Self::SixteenthTriplet,
+            13 => Self::ThirtySecond,
+            _ => panic!("Invalid division integer"),
+        }
+    }
+}
+
+impl Division {
+    pub const ALL: [Self; 13] = [
+        Self::FourBars,
+        Self::TwoBars,
+        Self::Bar,
+        Self::Half,
+        Self::Quarter,
+        Self::DottedQuarter,
+        Self::QuarterTriplet,
+        Self::Eighth,
+        Self::DottedEighth,
+        Self::EighthTriplet,
+        Self::Sixteenth,
+        Self::SixteenthTriplet,
+        Self::ThirtySecond,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FourBars => "4 bars",
+            Self::TwoBars => "2 bars",
+            Self::Bar => "1 bar",
+            Self::Half => "1/2",
+            Self::Quarter => "1/4",
+            Self::DottedQuarter => "1/4 dotted",
+            Self::QuarterTriplet => "1/4 triplet",
+            Self::Eighth => "1/8",
+            Self::DottedEighth => "1/8 dotted",
+            Self::EighthTriplet => "1/8 triplet",
+            Self::Sixteenth => "1/16",
+            Self::SixteenthTriplet => "1/16 triplet",
+            Self::ThirtySecond => "1/32",
+        }
+    }
+
+    /// Length in quarter notes, assuming 4/4.
+    pub fn beats(self) -> f32 {
+        match self {
+            Self::FourBars => 16.0,
+            Self::TwoBars => 8.0,
+            Self::Bar => 4.0,
+            Self::Half => 2.0,
+            Self::Quarter => 1.0,
+            Self::DottedQuarter => 1.5,
+            Self::QuarterTriplet => 2.0 / 3.0,
+            Self::Eighth => 0.5,
+            Self::DottedEighth => 0.75,
+            Self::EighthTriplet => 1.0 / 3.0,
+            Self::Sixteenth => 0.25,
+            Self::SixteenthTriplet => 1.0 / 6.0,
+            Self::ThirtySecond => 0.125,
+        }
+    }
+}
+
+/// Progress of one LFO, either a voice's own or the shared one.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LfoState {
+    phase: f32,
+    /// Random value of the previous and current cycle.
+    random: [f32; 2],
+    /// Seconds since the note started, for the fade-in.
+    elapsed: f32,
+    rng: Rng,
+}
+
+impl LfoState {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        Self {
+            phase: 0.0,
+            random: [rng.next_f32(), rng.next_f32()],
+            elapsed: 0.0,
+            rng,
+        }
+    }
+}
+
+pub struct Lfo {
+    pub shape: LfoShape,
+    /// Free-running rate in Hz.
+    pub rate: f32,
+    pub sync: bool,
+    pub division: Division,
+    /// Start of the cycle, `0.0..1.0`.
+    pub phase: f32,
+    /// Seconds to fade in after a note starts.
+    pub fade: f32,
+    pub mode: LfoMode,
+    shape_a: Arc<AtomicLfoShape>,
+    rate_a: Arc<AtomicF32>,
+    sync_a: Arc<AtomicBool>,
+    division_a: Arc<AtomicDivision>,
+    phase_a: Arc<AtomicF32>,
+    fade_a: Arc<AtomicF32>,
+    mode_a: Arc<AtomicLfoMode>,
+}
+
+impl Lfo {
+    pub fn new(lfo: params::Lfo) -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            rate: 1.0,
+            sync: false,
+            division: Division::Quarter,
+            phase: 0.0,
+            fade: 0.0,
+            mode: LfoMode::Voice,
+            shape_a: lfo.shape,
+            rate_a: lfo.rate,
+            sync_a: lfo.sync,
+            division_a: lfo.division,
+            phase_a: lfo.phase,
+            fade_a: lfo.fade,
+            mode_a: lfo.mode,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        self.shape = self.shape_a.load(Ordering::Acquire);
+        self.rate = self.rate_a.load(Ordering::Acquire);
+        self.sync = self.sync_a.load(Ordering::Acquire);
+        self.division = self.division_a.load(Ordering::Acquire);
+        self.phase = self.phase_a.load(Ordering::Acquire);
+        self.fade = self.fade_a.load(Ordering::Acquire);
+        self.mode = self.mode_a.load(Ordering::Acquire);
+    }
+
+    /// Cycles per second at `tempo` in beats per minute.
+    #[inline(always)]
+    pub fn freq(&self, tempo: f32) -> f32 {
+        if self.sync {
+            tempo / 60.0 / self.division.beats()
+        } else {
+            self.rate
+        }
+    }
+
+    /// Advances `state` by one sample and returns the new value,
+    /// `-1.0..=1.0`, without the fade-in.
+    #[inline(always)]
+    pub fn tick(&self, state: &mut LfoState, dt: f32) -> f32 {
+        state.phase += dt;
+        if state.phase >= 1.0 {
+            state.phase -= state.phase.floor();
+            state.random = [state.random[1], state.rng.next_f32()];
+        }
+
+        let phase = (state.phase + self.phase).fract();
+        match self.shape {
+            LfoShape::Sine => (phase * 2.0 * PI).sin(),
+            LfoShape::Triangle => 2.0 * (2.0 * phase - 1.0).abs() - 1.0,
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase > 0.5 {
+                    -1.0
+                } else {
+                    1.0
+                }
+            }
+            LfoShape::SampleHold => state.random[1],
+            LfoShape::SmoothRandom => {
+                let [from, to] = state.random;
+                let t = state.phase * state.phase * (3.0 - 2.0 * state.phase);
+                from + (to - from) * t
+            }
+        }
+    }
+
+    /// Level of the fade-in, counting from the start of the note.
+    #[inline(always)]
+    pub fn fade_in(&self, state: &mut LfoState, sample_rate: f32) -> f32 {
+        if state.elapsed >= self.fade {
+            return 1.0;
+        }
+
+        state.elapsed += 1.0 / sample_rate;
+        (state.elapsed / self.fade).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lfo(shape: LfoShape) -> Lfo {
+        let mut lfo = Lfo::new(params::Lfo::default());
+        lfo.update();
+        lfo.shape = shape;
+        lfo
+    }
+
+    #[test]
+    fn syncs_to_the_tempo() {
+        let mut lfo = lfo(LfoShape::Sine);
+        lfo.rate = 3.0;
+        assert_eq!(lfo.freq(120.0), 3.0);
+
+        lfo.sync = true;
+        for division in Division::ALL {
+            lfo.division = division;
+            // A quarter note lasts half a second at 120 BPM.
+            let expected = 2.0 / division.beats();
+            assert!((lfo.freq(120.0) - expected).abs() < 1e-5, "{division:?}");
+        }
+        lfo.division = Division::Bar;
+        assert_eq!(lfo.freq(60.0), 0.25);
+    }
+
+    #[test]
+    fn phase_offsets_the_cycle() {
+        let mut lfo = lfo(LfoShape::Saw);
+        let mut state = LfoState::new(0);
+        assert_eq!(lfo.tick(&mut state, 0.0), -1.0);
+
+        lfo.phase = 0.25;
+        let mut state = LfoState::new(0);
+        assert_eq!(lfo.tick(&mut state, 0.0), -0.5);
+        assert_eq!(lfo.tick(&mut state, 0.5), 0.5);
+        // Wraps around past the end of the cycle.
+        assert_eq!(lfo.tick(&mut state, 0.5), -0.5);
+
+        lfo.shape = LfoShape::Sine;
+        let mut state = LfoState::new(0);
+        assert!((lfo.tick(&mut state, 0.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fades_in_linearly() {
+        let mut lfo = lfo(LfoShape::Sine);
+        lfo.fade = 0.1;
+        let mut state = LfoState::new(0);
+        let levels: Vec<f32> = (0..200).map(|_| lfo.fade_in(&mut state, 1000.0)).collect();
+        assert!((levels[49] - 0.5).abs() < 1e-3, "{}", levels[49]);
+        assert!(levels[..100].windows(2).all(|pair| pair[1] > pair[0]));
+        assert!(levels[100..].iter().all(|&level| level == 1.0));
+
+        // The last step does not overshoot when the fade is not a whole
+        // number of samples.
+        lfo.fade = 0.0105;
+        let mut state = LfoState::new(0);
+        assert!((0..20).all(|_| lfo.fade_in(&mut state, 1000.0) <= 1.0));
+
+        lfo.fade = 0.0;
+        let mut state = LfoState::new(0);
+        assert_eq!(lfo.fade_in(&mut state, 1000.0), 1.0);
+    }
+}
@@ -16,9 +16,29 @@
  */
 
 pub mod atomicf;
+#[cfg(feature = "audio")]
+pub mod config;
+pub mod engine;
 pub mod envelope;
+pub mod event;
+pub mod filter;
+#[cfg(feature = "gui")]
 pub mod gui;
 pub mod keyboard;
+pub mod lfo;
+#[cfg(feature = "midi")]
+pub mod midi;
+pub mod modulation;
+pub mod mseg;
+pub mod noise;
 pub mod oscilator;
+pub mod params;
+pub mod render;
+pub mod ringbuf;
+#[cfg(feature = "audio")]
 pub mod synthesizer;
+pub mod velocity;
+pub mod voice;
+pub mod wav;
 pub mod waveform;
+pub mod wavetable;
@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    ffi::CString,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use alsa::{
+    poll::{self, Descriptors},
+    seq::{EvCtrl, EvNote, EventType, PortCap, PortType, Seq},
+    Direction,
+};
+
+use super::SharedSender;
+use crate::{event::Event, keyboard::Key};
+
+const CLIENT_NAME: &str = "VirtSynth";
+const PORT_NAME: &str = "midi_in";
+
+/// How often the input thread checks whether it should stop.
+const POLL_TIMEOUT_MS: i32 = 100;
+
+pub struct AlsaMidiInput {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AlsaMidiInput {
+    pub fn open(sender: SharedSender, dropped: Arc<AtomicUsize>) -> Result<Self, String> {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = Arc::clone(&running);
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        // The sequencer handle can't be moved between threads, so it is
+        // opened on the thread that reads from it.
+        let thread = thread::spawn(move || {
+            let seq = match open_seq() {
+                Ok(seq) => {
+                    let _ = ready_tx.send(Ok(()));
+                    seq
+                }
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+            run(&seq, &sender, &running_clone, &dropped);
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(Self {
+                running,
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => Err("ALSA MIDI thread exited unexpectedly".to_string()),
+        }
+    }
+}
+
+impl Drop for AlsaMidiInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn open_seq() -> Result<Seq, String> {
+    let seq = Seq::open(None, Some(Direction::Capture), true)
+        .map_err(|err| format!("Failed to open ALSA sequencer: {err}"))?;
+
+    let client_name = CString::new(CLIENT_NAME).unwrap();
+    seq.set_client_name(&client_name)
+        .map_err(|err| format!("Failed to name ALSA client: {err}"))?;
+
+    let port_name = CString::new(PORT_NAME).unwrap();
+    seq.create_simple_port(
+        &port_name,
+        PortCap::WRITE | PortCap::SUBS_WRITE,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )
+    .map_err(|err| format!("Failed to create ALSA port: {err}"))?;
+
+    Ok(seq)
+}
+
+fn run(seq: &Seq, sender: &SharedSender, running: &AtomicBool, dropped: &AtomicUsize) {
+    let mut fds = match (seq, Some(Direction::Capture)).get() {
+        Ok(fds) => fds,
+        Err(_) => return,
+    };
+    let mut input = seq.input();
+
+    while running.load(Ordering::Acquire) {
+        if poll::poll(&mut fds, POLL_TIMEOUT_MS).is_err() {
+            return;
+        }
+
+        while input.event_input_pending(true).unwrap_or(0) > 0 {
+            let Ok(event) = input.event_input() else {
+                break;
+            };
+
+            let Some(event) = convert(&event) else {
+                continue;
+            };
+
+            // The sequencer delivers events as they arrive, so they are
+            // played as soon as possible.
+            let sent = match sender.lock() {
+                Ok(mut sender) => sender.send(event),
+                Err(_) => false,
+            };
+            if !sent {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn convert(event: &alsa::seq::Event) -> Option<Event> {
+    match event.get_type() {
+        EventType::Noteon => {
+            let note: EvNote = event.get_data()?;
+            let key = Key::new(note.note)?;
+            if note.velocity == 0 {
+                Some(Event::NoteOff { key })
+            } else {
+                Some(Event::NoteOn {
+                    key,
+                    velocity: note.velocity as f32 / 127.0,
+                })
+            }
+        }
+        EventType::Noteoff => {
+            let note: EvNote = event.get_data()?;
+            Some(Event::NoteOff {
+                key: Key::new(note.note)?,
+            })
+        }
+        EventType::Keypress => {
+            let note: EvNote = event.get_data()?;
+            Some(Event::KeyPressure {
+                key: Key::new(note.note)?,
+                value: note.velocity as f32 / 127.0,
+            })
+        }
+        EventType::Controller => {
+            let ctrl: EvCtrl = event.get_data()?;
+            Some(Event::Controller {
+                number: u8::try_from(ctrl.param).ok()?,
+                value: ctrl.value.clamp(0, 127) as u8,
+            })
+        }
+        EventType::Chanpress => {
+            let ctrl: EvCtrl = event.get_data()?;
+            Some(Event::ChannelPressure {
+                value: ctrl.value.clamp(0, 127) as f32 / 127.0,
+            })
+        }
+        EventType::Pitchbend => {
+            let ctrl: EvCtrl = event.get_data()?;
+            Some(Event::PitchBend {
+                value: super::pitch_bend(ctrl.value),
+            })
+        }
+        _ => None,
+    }
+}
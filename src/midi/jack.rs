@@ -0,0 +1,75 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use jack::{
+    AsyncClient, Client, ClientOptions, Control, MidiIn, Port, ProcessHandler, ProcessScope,
+};
+
+use crate::event::EventSender;
+
+const CLIENT_NAME: &str = "VirtSynth MIDI";
+const PORT_NAME: &str = "midi_in";
+
+struct Handler {
+    port: Port<MidiIn>,
+    /// Owned by the JACK thread, so sending never waits for a lock.
+    sender: EventSender,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl ProcessHandler for Handler {
+    fn process(&mut self, _client: &Client, ps: &ProcessScope) -> Control {
+        // The JACK cycle and the engine's buffers run on separate clocks, so
+        // the offsets of events within the cycle can't be placed in a buffer.
+        // Events play at the start of the next one.
+        for raw in self.port.iter(ps) {
+            super::forward(&mut self.sender, raw.bytes, &self.dropped);
+        }
+
+        Control::Continue
+    }
+}
+
+pub struct JackMidiInput {
+    _client: AsyncClient<(), Handler>,
+}
+
+impl JackMidiInput {
+    /// JACK keeps the handler of a client that fails to activate, and
+    /// `sender` with it.
+    pub fn open(sender: EventSender, dropped: Arc<AtomicUsize>) -> Result<Self, String> {
+        let (client, _status) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+            .map_err(|err| format!("Failed to connect to JACK: {err}"))?;
+        let port = client
+            .register_port(PORT_NAME, MidiIn)
+            .map_err(|err| format!("Failed to register JACK MIDI port: {err}"))?;
+        let client = client
+            .activate_async(
+                (),
+                Handler {
+                    port,
+                    sender,
+                    dropped,
+                },
+            )
+            .map_err(|err| format!("Failed to activate JACK client: {err}"))?;
+
+        Ok(Self { _client: client })
+    }
+}
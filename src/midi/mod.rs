@@ -0,0 +1,277 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! MIDI input. Incoming messages are converted to [`Event`]s and queued for
+//! the engine from the backend's own thread.
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{
+    event::{self, Event, EventSender, SharedQueueSlot},
+    keyboard::Key,
+};
+
+mod alsa;
+mod jack;
+
+/// Event queue the ALSA input thread sends to. Waiting for the lock only
+/// holds up that thread.
+pub type SharedSender = Arc<Mutex<EventSender>>;
+
+/// Event queues for the backends and a count of the events they dropped
+/// because a queue was full.
+pub struct MidiQueues {
+    alsa: SharedSender,
+    /// Takes a new queue every time a JACK port opens, see
+    /// [`MidiQueues::jack_queue`].
+    jack: SharedQueueSlot,
+    /// The engine clock, for timestamping new queues.
+    clock: Arc<AtomicU64>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MidiQueues {
+    /// `jack` must be shared with the engine, see
+    /// [`Engine::share_queue`](crate::engine::Engine::share_queue).
+    pub fn new(alsa: EventSender, jack: SharedQueueSlot, clock: Arc<AtomicU64>) -> Self {
+        Self {
+            alsa: Arc::new(Mutex::new(alsa)),
+            jack,
+            clock,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Gives the engine a new JACK queue and returns its sending half. The
+    /// sender is moved into the JACK process callback, which must never wait
+    /// for a lock, and a client that fails to activate never gives it back.
+    fn jack_queue(&self) -> EventSender {
+        let (sender, consumer) = event::queue(Arc::clone(&self.clock));
+        self.jack.lock().unwrap().set(consumer);
+        sender
+    }
+
+    /// Events dropped by any backend so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum MidiBackend {
+    Jack,
+    Alsa,
+}
+
+pub enum MidiInput {
+    Jack(jack::JackMidiInput),
+    Alsa(alsa::AlsaMidiInput),
+}
+
+impl MidiInput {
+    /// Registers a MIDI input port with `backend`. The port stays open until
+    /// the returned value is dropped.
+    pub fn open(backend: MidiBackend, queues: &MidiQueues) -> Result<Self, String> {
+        let dropped = Arc::clone(&queues.dropped);
+        match backend {
+            MidiBackend::Jack => {
+                jack::JackMidiInput::open(queues.jack_queue(), dropped).map(Self::Jack)
+            }
+            MidiBackend::Alsa => {
+                alsa::AlsaMidiInput::open(Arc::clone(&queues.alsa), dropped).map(Self::Alsa)
+            }
+        }
+    }
+
+    pub fn backend(&self) -> MidiBackend {
+        match self {
+            Self::Jack(_) => MidiBackend::Jack,
+            Self::Alsa(_) => MidiBackend::Alsa,
+        }
+    }
+}
+
+/// Queues the raw MIDI message `bytes` to be played as soon as possible,
+/// counting it in `dropped` if the queue is full.
+fn forward(sender: &mut EventSender, bytes: &[u8], dropped: &AtomicUsize) {
+    if let Some(event) = parse(bytes) {
+        if !sender.send(event) {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Converts a raw MIDI channel message. Messages on every channel are
+/// accepted; anything the engine does not handle yields `None`.
+pub fn parse(bytes: &[u8]) -> Option<Event> {
+    let (&status, data) = bytes.split_first()?;
+    // Truncated messages are dropped rather than padded.
+    let data: [u8; 2] = match (status & 0xf0, data) {
+        (0xd0, [a, ..]) => [a & 0x7f, 0],
+        (0x80..=0xb0 | 0xe0, [a, b, ..]) => [a & 0x7f, b & 0x7f],
+        _ => return None,
+    };
+
+    match (status & 0xf0, data) {
+        (0x80, [note, _]) | (0x90, [note, 0]) => Some(Event::NoteOff {
+            key: Key::new(note)?,
+        }),
+        (0x90, [note, velocity]) => Some(Event::NoteOn {
+            key: Key::new(note)?,
+            velocity: velocity as f32 / 127.0,
+        }),
+        (0xa0, [note, pressure]) => Some(Event::KeyPressure {
+            key: Key::new(note)?,
+            value: pressure as f32 / 127.0,
+        }),
+        (0xb0, [number, value]) => Some(Event::Controller { number, value }),
+        (0xd0, [pressure, _]) => Some(Event::ChannelPressure {
+            value: pressure as f32 / 127.0,
+        }),
+        (0xe0, [lsb, msb]) => Some(Event::PitchBend {
+            value: pitch_bend(((msb as i32) << 7 | lsb as i32) - 8192),
+        }),
+        _ => None,
+    }
+}
+
+/// Normalizes a signed 14-bit pitch bend value to `-1.0..=1.0`.
+fn pitch_bend(value: i32) -> f32 {
+    (value as f32 / 8192.0).clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::Engine, params::Params};
+
+    fn key(note: u8) -> Key {
+        Key::new(note).unwrap()
+    }
+
+    #[test]
+    fn parses_notes() {
+        assert_eq!(
+            parse(&[0x90, 60, 127]),
+            Some(Event::NoteOn {
+                key: key(60),
+                velocity: 1.0
+            })
+        );
+        assert_eq!(
+            parse(&[0x83, 61, 64]),
+            Some(Event::NoteOff { key: key(61) })
+        );
+        // Note-on with velocity 0 is a note-off.
+        assert_eq!(parse(&[0x9f, 62, 0]), Some(Event::NoteOff { key: key(62) }));
+    }
+
+    #[test]
+    fn parses_pitch_bend() {
+        let bend = |lsb, msb| match parse(&[0xe0, lsb, msb]) {
+            Some(Event::PitchBend { value }) => value,
+            event => panic!("{event:?}"),
+        };
+        assert_eq!(bend(0x00, 0x40), 0.0);
+        assert_eq!(bend(0x00, 0x00), -1.0);
+        assert!((bend(0x7f, 0x7f) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn parses_aftertouch_and_controllers() {
+        assert_eq!(
+            parse(&[0xa0, 60, 127]),
+            Some(Event::KeyPressure {
+                key: key(60),
+                value: 1.0
+            })
+        );
+        assert_eq!(
+            parse(&[0xd0, 0]),
+            Some(Event::ChannelPressure { value: 0.0 })
+        );
+        assert_eq!(
+            parse(&[0xb0, 1, 64]),
+            Some(Event::Controller {
+                number: 1,
+                value: 64
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        for bytes in [
+            &[0x80, 60][..],
+            &[0x90, 60],
+            &[0xa0, 60],
+            &[0xb0, 1],
+            &[0xd0],
+            &[0xe0, 0x00],
+            &[0x90],
+            &[],
+        ] {
+            assert_eq!(parse(bytes), None, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn messages_play_through_the_engine() {
+        let clock = Arc::new(AtomicU64::new(0));
+        let (alsa, alsa_consumer) = event::queue(Arc::clone(&clock));
+        let mut engine = Engine::new(
+            48000.0,
+            Params::default(),
+            vec![alsa_consumer],
+            Arc::clone(&clock),
+        );
+        let jack = SharedQueueSlot::default();
+        engine.share_queue(Arc::clone(&jack));
+        let queues = MidiQueues::new(alsa, jack, clock);
+
+        // Peak level of the next half second.
+        let mut peak = || {
+            let mut buffer = vec![0.0; 2 * 24000];
+            engine.process(&mut buffer, 2);
+            buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+
+        let mut alsa = queues.alsa.lock().unwrap();
+        forward(&mut alsa, &[0x90, 60, 100], &queues.dropped);
+        assert!(peak() > 0.1);
+        forward(&mut alsa, &[0x80, 60, 0], &queues.dropped);
+        peak();
+        assert_eq!(peak(), 0.0);
+        drop(alsa);
+
+        // The queue of a JACK client that failed to activate is lost, the
+        // next one still reaches the engine.
+        let lost = queues.jack_queue();
+        let mut jack = queues.jack_queue();
+        drop(lost);
+        forward(&mut jack, &[0x90, 64, 100], &queues.dropped);
+        assert!(peak() > 0.1);
+        forward(&mut jack, &[0x90, 64, 0], &queues.dropped);
+        peak();
+        assert_eq!(peak(), 0.0);
+
+        assert_eq!(queues.dropped(), 0);
+    }
+}
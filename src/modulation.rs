@@ -0,0 +1,414 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Modulation matrix. Each slot adds a source, scaled by its amount, to a
+//! destination, separately for every voice.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::{
+    atomicf::{AtomicF32, AtomicModDestination, AtomicModSource},
+    lfo::LFOS,
+};
+
+/// Number of slots in the matrix.
+pub const MOD_SLOTS: usize = 8;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ModSource {
+    None = 0,
+    /// Note velocity, `0.0..=1.0`.
+    Velocity = 1,
+    ModWheel = 2,
+    /// Polyphonic or channel aftertouch, whichever is higher.
+    Aftertouch = 3,
+    /// The multi-segment envelope, `0.0..=1.0`.
+    Mseg = 4,
+    /// LFOs, `-1.0..=1.0`.
+    Lfo1 = 5,
+    Lfo2 = 6,
+}
+
+impl From<i32> for ModSource {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Velocity,
+            2 => Self::ModWheel,
+            3 => Self::Aftertouch,
+            4 => Self::Mseg,
+            5 => Self::Lfo1,
+            6 => Self::Lfo2,
+            _ => panic!("Invalid modulation source integer"),
+        }
+    }
+}
+
+impl ModSource {
+    pub const ALL: [Self; 7] = [
+        Self::None,
+        Self::Velocity,
+        Self::ModWheel,
+        Self::Aftertouch,
+        Self::Mseg,
+        Self::Lfo1,
+        Self::Lfo2,
+    ];
+
+    /// LFO sources, by LFO.
+    pub const LFO: [Self; LFOS] = [Self::Lfo1, Self::Lfo2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Velocity => "Velocity",
+            Self::ModWheel => "Mod wheel",
+            Self::Aftertouch => "Aftertouch",
+            Self::Mseg => "MSEG",
+            Self::Lfo1 => "LFO 1",
+            Self::Lfo2 => "LFO 2",
+        }
+    }
+}
+
+/// Parameters the matrix can modulate. Envelope times and curves, the LFO
+/// shape, phase and fade-in, and the unison count, curve and random phase
+/// are not destinations.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ModDestination {
+    None = 0,
+    /// Pitch of every oscillator.
+    Pitch = 1,
+    Osc1PulseWidth = 2,
+    Osc2PulseWidth = 3,
+    Osc3PulseWidth = 4,
+    Osc1Position = 5,
+    Osc2Position = 6,
+    Osc3Position = 7,
+    /// Filter cutoff, in octaves.
+    Cutoff = 8,
+    Resonance = 9,
+    /// Level of the voice, relative to its envelope.
+    Amplitude = 10,
+    Osc1Gain = 11,
+    Osc2Gain = 12,
+    Osc3Gain = 13,
+    /// FM received by an oscillator, relative to the indices into it.
+    Osc1Fm = 14,
+    Osc2Fm = 15,
+    Osc3Fm = 16,
+    /// Stereo position of the voice, `-1.0` is left.
+    Pan = 17,
+    /// Ladder drive.
+    FilterDrive = 18,
+    /// Filter envelope amount.
+    FilterEnvelope = 19,
+    /// Rate of an LFO in voice mode, in octaves. Global LFOs keep their
+    /// rate, they are shared by every voice.
+    Lfo1Rate = 20,
+    Lfo2Rate = 21,
+    /// Output of an LFO, relative to its full depth.
+    Lfo1Depth = 22,
+    Lfo2Depth = 23,
+    /// Unison detune of an oscillator, in cents.
+    Osc1Detune = 24,
+    Osc2Detune = 25,
+    Osc3Detune = 26,
+    /// Unison stereo spread of an oscillator.
+    Osc1Spread = 27,
+    Osc2Spread = 28,
+    Osc3Spread = 29,
+    /// Coarse tuning of an oscillator, in semitones.
+    Osc1Tune = 30,
+    Osc2Tune = 31,
+    Osc3Tune = 32,
+    /// Fine tuning of an oscillator, in cents.
+    Osc1Fine = 33,
+    Osc2Fine = 34,
+    Osc3Fine = 35,
+}
+
+impl From<i32> for ModDestination {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Pitch,
+            2 => Self::Osc1PulseWidth,
+            3 => Self::Osc2PulseWidth,
+            4 => Self::Osc3PulseWidth,
+            5 => Self::Osc1Position,
+            6 => Self::Osc2Position,
+            7 => Self::Osc3Position,
+            8 => Self::Cutoff,
+            9 => Self::Resonance,
+            10 => Self::Amplitude,
+            11 => Self::Osc1Gain,
+            12 => Self::Osc2Gain,
+            13 => Self::Osc3Gain,
+            14 => Self::Osc1Fm,
+            15 => Self::Osc2Fm,
+            16 => Self::Osc3Fm,
+            17 => Self::Pan,
+            18 => Self::FilterDrive,
+            19 => Self::FilterEnvelope,
+            20 => Self::Lfo1Rate,
+            21 => Self::Lfo2Rate,
+            22 => Self::Lfo1Depth,
+            23 => Self::Lfo2Depth,
+            24 => Self::Osc1Detune,
+            25 => Self::Osc2Detune,
+            26 => Self::Osc3Detune,
+            27 => Self::Osc1Spread,
+            28 => Self::Osc2Spread,
+            29 => Self::Osc3Spread,
+            30 => Self::Osc1Tune,
+            31 => Self::Osc2Tune,
+            32 => Self::Osc3Tune,
+            33 => Self::Osc1Fine,
+            34 => Self::Osc2Fine,
+            35 => Self::Osc3Fine,
+            _ => panic!("Invalid modulation destination integer"),
+        }
+    }
+}
+
+impl ModDestination {
+    pub const ALL: [Self; 36] = [
+        Self::None,
+        Self::Pitch,
+        Self::Osc1PulseWidth,
+        Self::Osc2PulseWidth,
+        Self::Osc3PulseWidth,
+        Self::Osc1Position,
+        Self::Osc2Position,
+        Self::Osc3Position,
+        Self::Cutoff,
+        Self::Resonance,
+        Self::Amplitude,
+        Self::Osc1Gain,
+        Self::Osc2Gain,
+        Self::Osc3Gain,
+        Self::Osc1Fm,
+        Self::Osc2Fm,
+        Self::Osc3Fm,
+        Self::Pan,
+        Self::FilterDrive,
+        Self::FilterEnvelope,
+        Self::Lfo1Rate,
+        Self::Lfo2Rate,
+        Self::Lfo1Depth,
+        Self::Lfo2Depth,
+        Self::Osc1Detune,
+        Self::Osc2Detune,
+        Self::Osc3Detune,
+        Self::Osc1Spread,
+        Self::Osc2Spread,
+        Self::Osc3Spread,
+        Self::Osc1Tune,
+        Self::Osc2Tune,
+        Self::Osc3Tune,
+        Self::Osc1Fine,
+        Self::Osc2Fine,
+        Self::Osc3Fine,
+    ];
+
+    /// Pulse width destinations, by oscillator.
+    pub const PULSE_WIDTH: [Self; 3] = [
+        Self::Osc1PulseWidth,
+        Self::Osc2PulseWidth,
+        Self::Osc3PulseWidth,
+    ];
+
+    /// Wavetable position destinations, by oscillator.
+    pub const POSITION: [Self; 3] = [Self::Osc1Position, Self::Osc2Position, Self::Osc3Position];
+
+    /// Gain destinations, by oscillator.
+    pub const GAIN: [Self; 3] = [Self::Osc1Gain, Self::Osc2Gain, Self::Osc3Gain];
+
+    /// FM destinations, by receiving oscillator.
+    pub const FM: [Self; 3] = [Self::Osc1Fm, Self::Osc2Fm, Self::Osc3Fm];
+
+    /// LFO rate destinations, by LFO.
+    pub const LFO_RATE: [Self; LFOS] = [Self::Lfo1Rate, Self::Lfo2Rate];
+
+    /// LFO depth destinations, by LFO.
+    pub const LFO_DEPTH: [Self; LFOS] = [Self::Lfo1Depth, Self::Lfo2Depth];
+
+    /// Unison detune destinations, by oscillator.
+    pub const DETUNE: [Self; 3] = [Self::Osc1Detune, Self::Osc2Detune, Self::Osc3Detune];
+
+    /// Unison stereo spread destinations, by oscillator.
+    pub const SPREAD: [Self; 3] = [Self::Osc1Spread, Self::Osc2Spread, Self::Osc3Spread];
+
+    /// Coarse tuning destinations, by oscillator.
+    pub const TUNE: [Self; 3] = [Self::Osc1Tune, Self::Osc2Tune, Self::Osc3Tune];
+
+    /// Fine tuning destinations, by oscillator.
+    pub const FINE: [Self; 3] = [Self::Osc1Fine, Self::Osc2Fine, Self::Osc3Fine];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Pitch => "Pitch",
+            Self::Osc1PulseWidth => "Osc 1 pulse width",
+            Self::Osc2PulseWidth => "Osc 2 pulse width",
+            Self::Osc3PulseWidth => "Osc 3 pulse width",
+            Self::Osc1Position => "Osc 1 position",
+            Self::Osc2Position => "Osc 2 position",
+            Self::Osc3Position => "Osc 3 position",
+            Self::Cutoff => "Cutoff",
+            Self::Resonance => "Resonance",
+            Self::Amplitude => "Amplitude",
+            Self::Osc1Gain => "Osc 1 volume",
+            Self::Osc2Gain => "Osc 2 volume",
+            Self::Osc3Gain => "Osc 3 volume",
+            Self::Osc1Fm => "Osc 1 FM",
+            Self::Osc2Fm => "Osc 2 FM",
+            Self::Osc3Fm => "Osc 3 FM",
+            Self::Pan => "Pan",
+            Self::FilterDrive => "Filter drive",
+            Self::FilterEnvelope => "Filter env amount",
+            Self::Lfo1Rate => "LFO 1 rate",
+            Self::Lfo2Rate => "LFO 2 rate",
+            Self::Lfo1Depth => "LFO 1 depth",
+            Self::Lfo2Depth => "LFO 2 depth",
+            Self::Osc1Detune => "Osc 1 unison detune",
+            Self::Osc2Detune => "Osc 2 unison detune",
+            Self::Osc3Detune => "Osc 3 unison detune",
+            Self::Osc1Spread => "Osc 1 unison spread",
+            Self::Osc2Spread => "Osc 2 unison spread",
+            Self::Osc3Spread => "Osc 3 unison spread",
+            Self::Osc1Tune => "Osc 1 tune",
+            Self::Osc2Tune => "Osc 2 tune",
+            Self::Osc3Tune => "Osc 3 tune",
+            Self::Osc1Fine => "Osc 1 fine",
+            Self::Osc2Fine => "Osc 2 fine",
+            Self::Osc3Fine => "Osc 3 fine",
+        }
+    }
+
+    /// Change at a source value of 1.0 and an amount of 1.0.
+    fn range(self) -> f32 {
+        match self {
+            Self::None => 0.0,
+            // Semitones.
+            Self::Pitch => 12.0,
+            Self::Osc1PulseWidth | Self::Osc2PulseWidth | Self::Osc3PulseWidth => 0.5,
+            Self::Osc1Position | Self::Osc2Position | Self::Osc3Position => 1.0,
+            // Octaves.
+            Self::Cutoff => 6.0,
+            Self::Resonance => 1.0,
+            // Added to a factor of 1.0.
+            Self::Amplitude | Self::Osc1Fm | Self::Osc2Fm | Self::Osc3Fm => 1.0,
+            Self::Osc1Gain | Self::Osc2Gain | Self::Osc3Gain => 1.0,
+            Self::Pan => 1.0,
+            Self::FilterDrive | Self::FilterEnvelope => 1.0,
+            // Octaves.
+            Self::Lfo1Rate | Self::Lfo2Rate => 4.0,
+            Self::Lfo1Depth | Self::Lfo2Depth => 1.0,
+            // Cents.
+            Self::Osc1Detune | Self::Osc2Detune | Self::Osc3Detune => 100.0,
+            Self::Osc1Spread | Self::Osc2Spread | Self::Osc3Spread => 1.0,
+            // Semitones.
+            Self::Osc1Tune | Self::Osc2Tune | Self::Osc3Tune => 12.0,
+            // Cents.
+            Self::Osc1Fine | Self::Osc2Fine | Self::Osc3Fine => 100.0,
+        }
+    }
+}
+
+/// A row of the matrix.
+#[derive(Clone)]
+pub struct ModSlot {
+    pub source: Arc<AtomicModSource>,
+    pub destination: Arc<AtomicModDestination>,
+    /// `-1.0..=1.0` of the destination's range.
+    pub amount: Arc<AtomicF32>,
+}
+
+impl Default for ModSlot {
+    fn default() -> Self {
+        Self {
+            source: Arc::new(AtomicModSource::new(ModSource::None)),
+            destination: Arc::new(AtomicModDestination::new(ModDestination::None)),
+            amount: Arc::new(AtomicF32::new(0.0)),
+        }
+    }
+}
+
+/// Current values of the sources for one voice.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Sources([f32; ModSource::ALL.len()]);
+
+impl Sources {
+    #[inline(always)]
+    pub fn set(&mut self, source: ModSource, value: f32) {
+        self.0[source as usize] = value;
+    }
+}
+
+/// Sum of the modulation at each destination for one voice.
+#[derive(Clone, Copy, Debug)]
+pub struct Targets([f32; ModDestination::ALL.len()]);
+
+impl Default for Targets {
+    fn default() -> Self {
+        Self([0.0; ModDestination::ALL.len()])
+    }
+}
+
+impl Targets {
+    #[inline(always)]
+    pub fn get(&self, destination: ModDestination) -> f32 {
+        self.0[destination as usize]
+    }
+}
+
+pub struct Modulation {
+    slots: [(ModSource, ModDestination, f32); MOD_SLOTS],
+    slots_a: [ModSlot; MOD_SLOTS],
+}
+
+impl Modulation {
+    pub fn new(slots_a: [ModSlot; MOD_SLOTS]) -> Self {
+        Self {
+            slots: [(ModSource::None, ModDestination::None, 0.0); MOD_SLOTS],
+            slots_a,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        for (slot, slot_a) in self.slots.iter_mut().zip(self.slots_a.iter()) {
+            *slot = (
+                slot_a.source.load(Ordering::Acquire),
+                slot_a.destination.load(Ordering::Acquire),
+                slot_a.amount.load(Ordering::Acquire),
+            );
+        }
+    }
+
+    #[inline(always)]
+    pub fn apply(&self, sources: &Sources) -> Targets {
+        let mut targets = Targets::default();
+        for &(source, destination, amount) in self.slots.iter() {
+            targets.0[destination as usize] +=
+                sources.0[source as usize] * amount * destination.range();
+        }
+        targets
+    }
+}
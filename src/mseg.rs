@@ -0,0 +1,389 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Multi-segment envelope: a free-form shape drawn from breakpoints, used as a
+//! modulation source.
+
+use std::sync::{Arc, Mutex};
+
+use crate::envelope::curve;
+
+/// Most breakpoints a shape can have.
+pub const MAX_POINTS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Point {
+    /// Seconds from the start of the envelope.
+    pub time: f32,
+    /// `0.0..=1.0`.
+    pub level: f32,
+    /// Shape of the segment ending at this point, see [`curve`].
+    pub curve: f32,
+}
+
+/// Breakpoints of an envelope. The first point is at time zero and the times
+/// never decrease.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shape {
+    points: [Point; MAX_POINTS],
+    len: usize,
+    /// Point held while the key is down.
+    pub sustain: Option<usize>,
+    /// Point the envelope jumps back to on reaching the sustain point, which
+    /// then repeats the segments in between instead of holding.
+    pub loop_start: Option<usize>,
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        let mut shape = Self {
+            points: [Point::default(); MAX_POINTS],
+            len: 0,
+            sustain: None,
+            loop_start: None,
+        };
+        for (time, level) in [(0.0, 0.0), (0.1, 1.0), (0.4, 0.5), (1.0, 0.0)] {
+            shape.insert(Point {
+                time,
+                level,
+                curve: 0.0,
+            });
+        }
+        shape.sustain = Some(2);
+        shape
+    }
+}
+
+impl Shape {
+    #[inline(always)]
+    pub fn points(&self) -> &[Point] {
+        &self.points[..self.len]
+    }
+
+    /// Time of the last point.
+    pub fn length(&self) -> f32 {
+        self.points().last().map_or(0.0, |point| point.time)
+    }
+
+    /// Adds `point` in time order. Returns its index, or `None` if the shape
+    /// is full.
+    pub fn insert(&mut self, point: Point) -> Option<usize> {
+        if self.len == MAX_POINTS {
+            return None;
+        }
+
+        let index = self.points().partition_point(|p| p.time <= point.time);
+        self.points.copy_within(index..self.len, index + 1);
+        self.points[index] = point;
+        self.len += 1;
+
+        for marker in [&mut self.sustain, &mut self.loop_start] {
+            if let Some(marker) = marker.as_mut().filter(|marker| **marker >= index) {
+                *marker += 1;
+            }
+        }
+        Some(index)
+    }
+
+    /// Removes the point at `index`. The first point and the last remaining
+    /// segment are kept.
+    pub fn remove(&mut self, index: usize) {
+        if index == 0 || index >= self.len || self.len <= 2 {
+            return;
+        }
+
+        self.points.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+
+        for marker in [&mut self.sustain, &mut self.loop_start] {
+            *marker = match *marker {
+                Some(marker) if marker == index => None,
+                Some(marker) if marker > index => Some(marker - 1),
+                marker => marker,
+            };
+        }
+    }
+
+    /// Moves the point at `index`, keeping it between its neighbours. The
+    /// first point stays at time zero.
+    pub fn set(&mut self, index: usize, time: f32, level: f32) {
+        let min = if index == 0 {
+            0.0
+        } else {
+            self.points[index - 1].time
+        };
+        let max = if index == 0 {
+            0.0
+        } else if index + 1 < self.len {
+            self.points[index + 1].time
+        } else {
+            f32::MAX
+        };
+
+        let point = &mut self.points[index];
+        point.time = time.clamp(min, max);
+        point.level = level.clamp(0.0, 1.0);
+    }
+
+    pub fn set_curve(&mut self, index: usize, curve: f32) {
+        self.points[index].curve = curve.clamp(-1.0, 1.0);
+    }
+
+    /// Level `t` of the way through the segment ending at point `index`,
+    /// starting from `from`.
+    #[inline(always)]
+    pub fn segment(&self, index: usize, from: f32, t: f32) -> f32 {
+        let point = &self.points[index];
+        from + (point.level - from) * curve(t, point.curve)
+    }
+}
+
+/// Per-voice progress through a [`Shape`].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MsegState {
+    /// Index of the point the envelope is moving towards. Zero holds at the
+    /// first point.
+    target: usize,
+    /// Seconds into the current segment.
+    position: f32,
+    /// Level the current segment started from.
+    from: f32,
+    pub level: f32,
+    released: bool,
+}
+
+impl MsegState {
+    #[inline(always)]
+    pub fn release(&mut self) {
+        self.released = true;
+    }
+}
+
+/// Plays the [`Shape`] shared with the GUI.
+pub struct Mseg {
+    shape: Shape,
+    shape_a: Arc<Mutex<Shape>>,
+}
+
+impl Mseg {
+    pub fn new(shape_a: Arc<Mutex<Shape>>) -> Self {
+        Self {
+            shape: Shape::default(),
+            shape_a,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        // A shape being edited is picked up on a later buffer.
+        if let Ok(shape) = self.shape_a.try_lock() {
+            self.shape = *shape;
+        }
+    }
+
+    /// Restarts the envelope from its first point, where it stays until
+    /// released if that is the sustain point.
+    #[inline(always)]
+    pub fn press(&self, state: &mut MsegState) {
+        let first = self.shape.points()[0].level;
+        *state = MsegState {
+            target: if self.shape.sustain == Some(0) { 0 } else { 1 },
+            position: 0.0,
+            from: first,
+            level: first,
+            released: false,
+        };
+    }
+
+    /// Advances the envelope by one sample and returns its level.
+    #[inline(always)]
+    pub fn tick(&self, state: &mut MsegState, sample_rate: f32) -> f32 {
+        let points = self.shape.points();
+        let sustain = self.shape.sustain.filter(|&sustain| sustain < points.len());
+
+        // Releasing before the sustain point is reached, or while looping,
+        // skips ahead to the segment after it.
+        if let Some(sustain) = sustain {
+            if state.released && state.target <= sustain {
+                state.target = sustain + 1;
+                state.position = 0.0;
+                state.from = state.level;
+            }
+        }
+
+        if state.target == 0 || state.target >= points.len() {
+            return state.level;
+        }
+
+        let start = points[state.target - 1].time;
+        let duration = points[state.target].time - start;
+        state.position += 1.0 / sample_rate;
+
+        if state.position < duration {
+            state.level = self
+                .shape
+                .segment(state.target, state.from, state.position / duration);
+            return state.level;
+        }
+
+        // Reached the target point.
+        state.level = points[state.target].level;
+        state.from = state.level;
+        state.position = 0.0;
+
+        if !state.released && Some(state.target) == sustain {
+            match self.shape.loop_start {
+                // Continue from the current level, so the loop is seamless
+                // even if the levels at its ends differ.
+                Some(loop_start) if loop_start < state.target => state.target = loop_start + 1,
+                // Hold until released.
+                _ => {}
+            }
+        } else {
+            state.target += 1;
+        }
+
+        state.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn point(time: f32, level: f32) -> Point {
+        Point {
+            time,
+            level,
+            curve: 0.0,
+        }
+    }
+
+    fn mseg(shape: Shape) -> Mseg {
+        let mut mseg = Mseg::new(Arc::new(Mutex::new(shape)));
+        mseg.update();
+        mseg
+    }
+
+    /// Levels of the next `seconds` of ticks.
+    fn run(mseg: &Mseg, state: &mut MsegState, seconds: f32) -> Vec<f32> {
+        (0..(seconds * SAMPLE_RATE) as usize)
+            .map(|_| mseg.tick(state, SAMPLE_RATE))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_points_in_time_order() {
+        let mut shape = Shape::default();
+        assert_eq!(shape.insert(point(0.2, 0.3)), Some(2));
+        assert_eq!(shape.insert(point(2.0, 0.0)), Some(5));
+        let times: Vec<f32> = shape.points().iter().map(|p| p.time).collect();
+        assert_eq!(times, [0.0, 0.1, 0.2, 0.4, 1.0, 2.0]);
+        // The sustain marker follows its point.
+        assert_eq!(shape.sustain, Some(3));
+
+        shape.remove(3);
+        assert_eq!(shape.sustain, None);
+        shape.remove(0);
+        assert_eq!(shape.points().len(), 5);
+
+        // Points stay between their neighbours, the first at time zero.
+        shape.set(1, 5.0, 2.0);
+        assert_eq!(shape.points()[1], point(0.2, 1.0));
+        shape.set(0, 1.0, 0.5);
+        assert_eq!(shape.points()[0], point(0.0, 0.5));
+
+        while shape.insert(point(0.5, 0.5)).is_some() {}
+        assert_eq!(shape.points().len(), MAX_POINTS);
+    }
+
+    #[test]
+    fn holds_at_the_sustain_point() {
+        // Sustains at 0.5 from 0.4 s.
+        let mseg = mseg(Shape::default());
+        let mut state = MsegState::default();
+        mseg.press(&mut state);
+
+        let levels = run(&mseg, &mut state, 2.0);
+        assert!((levels[49] - 0.5).abs() < 0.01);
+        assert!((levels[99] - 1.0).abs() < 0.01);
+        assert!(levels[400..].iter().all(|&level| level == 0.5));
+    }
+
+    #[test]
+    fn loops_back_from_the_sustain_point() {
+        // Up to 1.0 at 0.1 s and back to 0.0 at 0.2 s.
+        let mut shape = Shape::default();
+        shape.remove(2);
+        shape.set(2, 0.2, 0.0);
+        shape.sustain = Some(2);
+        shape.loop_start = Some(0);
+        let mseg = mseg(shape);
+        let mut state = MsegState::default();
+        mseg.press(&mut state);
+
+        let levels = run(&mseg, &mut state, 1.0);
+        for cycle in 0..5 {
+            let start = 200 * cycle;
+            assert!((levels[start + 49] - 0.5).abs() < 0.02, "cycle {cycle}");
+            assert!((levels[start + 99] - 1.0).abs() < 0.02, "cycle {cycle}");
+            assert!(levels[start + 199] < 0.02, "cycle {cycle}");
+        }
+    }
+
+    #[test]
+    fn releases_from_the_current_level() {
+        let mseg = mseg(Shape::default());
+        let mut state = MsegState::default();
+        mseg.press(&mut state);
+
+        // Halfway up the attack, before the sustain point.
+        let before = *run(&mseg, &mut state, 0.05).last().unwrap();
+        state.release();
+        let levels = run(&mseg, &mut state, 1.0);
+
+        assert!(
+            (levels[0] - before).abs() < 0.01,
+            "{before} -> {}",
+            levels[0]
+        );
+        assert!(levels.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(*levels.last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn holds_at_a_sustain_on_the_first_point() {
+        let mut shape = Shape::default();
+        shape.set(0, 0.0, 0.25);
+        shape.sustain = Some(0);
+        let mseg = mseg(shape);
+        let mut state = MsegState::default();
+        mseg.press(&mut state);
+
+        let levels = run(&mseg, &mut state, 1.0);
+        assert!(levels.iter().all(|&level| level == 0.25));
+
+        // Then plays the whole shape from the first point.
+        state.release();
+        let levels = run(&mseg, &mut state, 1.5);
+        assert!((levels[49] - 0.625).abs() < 0.01);
+        assert!((levels[99] - 1.0).abs() < 0.01);
+        assert_eq!(*levels.last().unwrap(), 0.0);
+    }
+}
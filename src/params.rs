@@ -0,0 +1,270 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicI32, AtomicUsize},
+    Arc, Mutex,
+};
+
+use crate::{
+    atomicf::{
+        AtomicDivision, AtomicF32, AtomicFilterMode, AtomicFilterType, AtomicFmMode, AtomicLfoMode,
+        AtomicLfoShape, AtomicOscRef, AtomicQuality, AtomicSlope, AtomicStealPolicy,
+        AtomicVelocityCurve, AtomicWaveform,
+    },
+    filter::{FilterMode, FilterType, Slope, CUTOFF_RANGE},
+    lfo::{Division, LfoMode, LfoShape, LFOS},
+    modulation::{ModSlot, MOD_SLOTS},
+    mseg::Shape,
+    oscilator::{FmMode, OscRef, Quality},
+    velocity::VelocityCurve,
+    voice::StealPolicy,
+    waveform::Waveform,
+    wavetable::{Wavetable, WavetableSlot},
+};
+
+#[derive(Clone)]
+pub struct Osc {
+    pub active: Arc<AtomicBool>,
+    pub waveform: Arc<AtomicWaveform>,
+    pub gain: Arc<AtomicF32>,
+    pub quality: Arc<AtomicQuality>,
+    /// Table played by [`Waveform::Wavetable`]. Load a new one with
+    /// [`WavetableSlot::set`].
+    pub wavetable: Arc<Mutex<WavetableSlot>>,
+    /// Position in the wavetable, `0.0..=1.0` from the first to the last
+    /// frame.
+    pub position: Arc<AtomicF32>,
+    /// Fraction of the square's cycle spent high.
+    pub pulse_width: Arc<AtomicF32>,
+    pub octave: Arc<AtomicI32>,
+    pub semitone: Arc<AtomicI32>,
+    /// Fine tuning in cents.
+    pub cents: Arc<AtomicF32>,
+    /// Number of unison copies, `1..=MAX_UNISON`.
+    pub unison: Arc<AtomicUsize>,
+    /// Detune between the outermost copies and the centre, in cents.
+    pub unison_detune: Arc<AtomicF32>,
+    /// `0.0` spaces the copies evenly, towards `1.0` they bunch up around
+    /// the centre.
+    pub unison_curve: Arc<AtomicF32>,
+    /// How far the copies are panned apart, `0.0..=1.0`.
+    pub unison_stereo: Arc<AtomicF32>,
+    /// How much the start phase of each copy is randomized, `0.0..=1.0`.
+    pub random_phase: Arc<AtomicF32>,
+    /// Oscillator whose cycle restarts this one's.
+    pub sync: Arc<AtomicOscRef>,
+    /// Oscillator this one is multiplied with.
+    pub ring: Arc<AtomicOscRef>,
+}
+
+impl Osc {
+    pub fn new(active: bool, waveform: Waveform, gain: f32) -> Self {
+        Self {
+            active: Arc::new(AtomicBool::new(active)),
+            waveform: Arc::new(AtomicWaveform::new(waveform)),
+            gain: Arc::new(AtomicF32::new(gain)),
+            quality: Arc::new(AtomicQuality::new(Quality::Standard)),
+            wavetable: Arc::new(Mutex::new(WavetableSlot::new(Wavetable::basic()))),
+            position: Arc::new(AtomicF32::new(0.0)),
+            pulse_width: Arc::new(AtomicF32::new(0.5)),
+            octave: Arc::new(AtomicI32::new(0)),
+            semitone: Arc::new(AtomicI32::new(0)),
+            cents: Arc::new(AtomicF32::new(0.0)),
+            unison: Arc::new(AtomicUsize::new(1)),
+            unison_detune: Arc::new(AtomicF32::new(20.0)),
+            unison_curve: Arc::new(AtomicF32::new(0.0)),
+            unison_stereo: Arc::new(AtomicF32::new(0.5)),
+            random_phase: Arc::new(AtomicF32::new(0.0)),
+            sync: Arc::new(AtomicOscRef::new(OscRef::Off)),
+            ring: Arc::new(AtomicOscRef::new(OscRef::Off)),
+        }
+    }
+}
+
+/// Times in seconds.
+#[derive(Clone)]
+pub struct Envelope {
+    pub delay: Arc<AtomicF32>,
+    pub attack: Arc<AtomicF32>,
+    pub hold: Arc<AtomicF32>,
+    pub decay: Arc<AtomicF32>,
+    pub sustain: Arc<AtomicF32>,
+    pub release: Arc<AtomicF32>,
+    /// Segment shapes, see [`envelope::curve`](crate::envelope::curve).
+    pub attack_curve: Arc<AtomicF32>,
+    pub decay_curve: Arc<AtomicF32>,
+    pub release_curve: Arc<AtomicF32>,
+    /// Cycle attack, hold and decay while the key is held.
+    pub looping: Arc<AtomicBool>,
+}
+
+impl Envelope {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self {
+            delay: Arc::new(AtomicF32::new(0.0)),
+            attack: Arc::new(AtomicF32::new(attack)),
+            hold: Arc::new(AtomicF32::new(0.0)),
+            decay: Arc::new(AtomicF32::new(decay)),
+            sustain: Arc::new(AtomicF32::new(sustain)),
+            release: Arc::new(AtomicF32::new(release)),
+            attack_curve: Arc::new(AtomicF32::new(0.0)),
+            decay_curve: Arc::new(AtomicF32::new(-0.5)),
+            release_curve: Arc::new(AtomicF32::new(-0.5)),
+            looping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Frequency and phase modulation between the oscillators.
+#[derive(Clone)]
+pub struct Fm {
+    pub mode: Arc<AtomicFmMode>,
+    /// Modulation index from the oscillator of the row to the oscillator of
+    /// the column.
+    pub index: [[Arc<AtomicF32>; 3]; 3],
+}
+
+impl Default for Fm {
+    fn default() -> Self {
+        Self {
+            mode: Arc::new(AtomicFmMode::new(FmMode::Phase)),
+            index: std::array::from_fn(|_| std::array::from_fn(|_| Arc::new(AtomicF32::new(0.0)))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Filter {
+    pub active: Arc<AtomicBool>,
+    pub kind: Arc<AtomicFilterType>,
+    /// Response of the state variable filter.
+    pub mode: Arc<AtomicFilterMode>,
+    /// Roll-off of the ladder.
+    pub slope: Arc<AtomicSlope>,
+    /// Ladder input gain, `0.0..=1.0`.
+    pub drive: Arc<AtomicF32>,
+    /// Cutoff in Hz.
+    pub cutoff: Arc<AtomicF32>,
+    /// `0.0..=1.0`.
+    pub resonance: Arc<AtomicF32>,
+    pub envelope: Envelope,
+    /// How far the envelope moves the cutoff, `-1.0..=1.0` of
+    /// [`ENVELOPE_RANGE`](crate::filter::ENVELOPE_RANGE).
+    pub envelope_amount: Arc<AtomicF32>,
+    /// How much lower velocities scale down the envelope, `0.0..=1.0`.
+    pub velocity: Arc<AtomicF32>,
+    /// How closely the cutoff follows the key, `0.0..=1.0` from not at all
+    /// to one octave per octave. Middle C leaves it unchanged.
+    pub key_track: Arc<AtomicF32>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            active: Arc::new(AtomicBool::new(false)),
+            kind: Arc::new(AtomicFilterType::new(FilterType::Svf)),
+            mode: Arc::new(AtomicFilterMode::new(FilterMode::LowPass)),
+            slope: Arc::new(AtomicSlope::new(Slope::Db24)),
+            drive: Arc::new(AtomicF32::new(0.0)),
+            cutoff: Arc::new(AtomicF32::new(CUTOFF_RANGE.1)),
+            resonance: Arc::new(AtomicF32::new(0.0)),
+            envelope: Envelope::new(0.01, 0.5, 0.0, 0.1),
+            envelope_amount: Arc::new(AtomicF32::new(0.0)),
+            velocity: Arc::new(AtomicF32::new(0.0)),
+            key_track: Arc::new(AtomicF32::new(0.0)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lfo {
+    pub shape: Arc<AtomicLfoShape>,
+    /// Rate in Hz when not synced.
+    pub rate: Arc<AtomicF32>,
+    /// Follow [`Params::tempo`] instead of `rate`.
+    pub sync: Arc<AtomicBool>,
+    pub division: Arc<AtomicDivision>,
+    /// Start of the cycle, `0.0..1.0`.
+    pub phase: Arc<AtomicF32>,
+    /// Seconds to fade in after a note starts.
+    pub fade: Arc<AtomicF32>,
+    pub mode: Arc<AtomicLfoMode>,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self {
+            shape: Arc::new(AtomicLfoShape::new(LfoShape::Sine)),
+            rate: Arc::new(AtomicF32::new(2.0)),
+            sync: Arc::new(AtomicBool::new(false)),
+            division: Arc::new(AtomicDivision::new(Division::Quarter)),
+            phase: Arc::new(AtomicF32::new(0.0)),
+            fade: Arc::new(AtomicF32::new(0.0)),
+            mode: Arc::new(AtomicLfoMode::new(LfoMode::Voice)),
+        }
+    }
+}
+
+/// Parameters shared between the GUI and the audio thread. Cloning is cheap
+/// and the clone refers to the same values.
+#[derive(Clone)]
+pub struct Params {
+    pub gain: Arc<AtomicF32>,
+    /// Amplitude envelope.
+    pub envelope: Envelope,
+    pub osc1: Osc,
+    pub osc2: Osc,
+    pub osc3: Osc,
+    pub polyphony: Arc<AtomicUsize>,
+    pub steal_policy: Arc<AtomicStealPolicy>,
+    pub velocity_curve: Arc<AtomicVelocityCurve>,
+    pub velocity_amount: Arc<AtomicF32>,
+    /// Pitch bend range in semitones.
+    pub pitch_bend_range: Arc<AtomicF32>,
+    pub modulation: [ModSlot; MOD_SLOTS],
+    pub fm: Fm,
+    pub filter: Filter,
+    /// Breakpoints of the multi-segment envelope.
+    pub mseg: Arc<Mutex<Shape>>,
+    pub lfos: [Lfo; LFOS],
+    /// Beats per minute, for tempo-synced LFOs.
+    pub tempo: Arc<AtomicF32>,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            gain: Arc::new(AtomicF32::new(0.5)),
+            envelope: Envelope::new(0.1, 0.0, 1.0, 0.1),
+            osc1: Osc::new(true, Waveform::Sin, 1.0),
+            osc2: Osc::new(false, Waveform::Sin, 1.0),
+            osc3: Osc::new(false, Waveform::Sin, 1.0),
+            polyphony: Arc::new(AtomicUsize::new(16)),
+            steal_policy: Arc::new(AtomicStealPolicy::new(StealPolicy::Oldest)),
+            velocity_curve: Arc::new(AtomicVelocityCurve::new(VelocityCurve::Linear)),
+            velocity_amount: Arc::new(AtomicF32::new(1.0)),
+            pitch_bend_range: Arc::new(AtomicF32::new(2.0)),
+            modulation: Default::default(),
+            fm: Fm::default(),
+            filter: Filter::default(),
+            mseg: Arc::new(Mutex::new(Shape::default())),
+            lfos: Default::default(),
+            tempo: Arc::new(AtomicF32::new(120.0)),
+        }
+    }
+}
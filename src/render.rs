@@ -0,0 +1,232 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Offline rendering of scripted note sequences, without an audio device.
+//!
+//! A score is a text file with one event per line:
+//!
+//! ```text
+//! # seconds  event  note  [velocity]
+//! 0.0        on     C4    0.8
+//! 0.5        off    C4
+//! 0.5        on     64
+//! 1.0        off    64
+//! ```
+//!
+//! Notes are MIDI note numbers or names such as `F#3`. Velocity is in
+//! `0.0..=1.0` and defaults to 1.0. Lines starting with `#` are ignored.
+
+use std::{error::Error, fmt};
+
+use crate::{engine::Engine, event::Event, keyboard::Key, params::Params};
+
+/// Frames rendered per engine buffer.
+const BLOCK_FRAMES: usize = 256;
+
+/// An event at a time in seconds from the start of the score.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ScoreEvent {
+    pub time: f64,
+    pub event: Event,
+}
+
+#[derive(Debug)]
+pub struct ScoreError {
+    /// One-based line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ScoreError {}
+
+/// Parses a score, returning its events sorted by time.
+pub fn parse_score(text: &str) -> Result<Vec<ScoreEvent>, ScoreError> {
+    let mut events = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| ScoreError {
+            line: index + 1,
+            message,
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (time, kind, key) = match fields[..] {
+            [time, kind, key] | [time, kind, key, _] => (time, kind, key),
+            _ => {
+                return Err(error(
+                    "expected <seconds> <on|off> <note> [velocity]".into(),
+                ))
+            }
+        };
+
+        let time: f64 = time
+            .parse()
+            .ok()
+            .filter(|time: &f64| *time >= 0.0)
+            .ok_or_else(|| error(format!("invalid time \"{time}\"")))?;
+        let key: Key = key.parse().map_err(error)?;
+
+        let event = match kind {
+            "on" => {
+                let velocity = match fields.get(3) {
+                    Some(velocity) => velocity
+                        .parse()
+                        .ok()
+                        .filter(|v| (0.0..=1.0).contains(v))
+                        .ok_or_else(|| error(format!("invalid velocity \"{velocity}\"")))?,
+                    None => 1.0,
+                };
+                Event::NoteOn { key, velocity }
+            }
+            "off" if fields.len() == 3 => Event::NoteOff { key },
+            _ => return Err(error(format!("invalid event \"{line}\""))),
+        };
+
+        events.push(ScoreEvent { time, event });
+    }
+
+    events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(events)
+}
+
+/// Renders `score` to interleaved samples. Rendering continues for `tail`
+/// seconds after the last event so releases can finish. The output only
+/// depends on the arguments, noise is generated from `seed`.
+pub fn render(
+    params: Params,
+    score: &[ScoreEvent],
+    sample_rate: u32,
+    channels: u16,
+    tail: f64,
+    seed: u64,
+) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let to_frames = |seconds: f64| (seconds * sample_rate as f64).round() as u64;
+
+    let end = to_frames(score.last().map_or(0.0, |event| event.time) + tail);
+
+    let (mut engine, mut sender) = Engine::with_queue(sample_rate as f32, params);
+    engine.set_seed(seed);
+
+    let mut output = vec![0.0; end as usize * channels];
+    let mut pending = score.iter().peekable();
+    for (block, buffer) in output.chunks_mut(BLOCK_FRAMES * channels).enumerate() {
+        // Only queue what is due in this block, a long score would not fit.
+        let block_end = ((block + 1) * BLOCK_FRAMES) as u64;
+        while let Some(event) = pending.peek() {
+            let time = to_frames(event.time);
+            if time >= block_end || !sender.send_at(time, event.event) {
+                break;
+            }
+            pending.next();
+        }
+
+        engine.process(buffer, channels);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::waveform::Waveform;
+
+    #[test]
+    fn parses_and_sorts_score() {
+        let score = parse_score(
+            "# chord\n\
+             1.5 off C4\n\
+             0 on 60 0.5\n\
+             \n\
+             0.25 on E4\n",
+        )
+        .unwrap();
+
+        let e4 = "E4".parse().unwrap();
+        assert_eq!(
+            score,
+            [
+                ScoreEvent {
+                    time: 0.0,
+                    event: Event::NoteOn {
+                        key: Key::C4,
+                        velocity: 0.5
+                    }
+                },
+                ScoreEvent {
+                    time: 0.25,
+                    event: Event::NoteOn {
+                        key: e4,
+                        velocity: 1.0
+                    }
+                },
+                ScoreEvent {
+                    time: 1.5,
+                    event: Event::NoteOff { key: Key::C4 }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_line_of_error() {
+        for (text, line) in [
+            ("0 on C4\n1 off", 2),
+            ("-1 on C4", 1),
+            ("0 on C4 2.0", 1),
+            ("0 off C4 1.0", 1),
+            ("\n# c\n0 hold C4", 3),
+            ("0 on H4", 1),
+        ] {
+            assert_eq!(parse_score(text).unwrap_err().line, line, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn renders_noise_reproducibly() {
+        let score = parse_score("0 on C4\n0.05 off C4").unwrap();
+        for waveform in [
+            Waveform::WhiteNoise,
+            Waveform::PinkNoise,
+            Waveform::BrownNoise,
+        ] {
+            let render = |seed| {
+                let params = Params::default();
+                params.osc1.waveform.store(waveform, Ordering::Release);
+                render(params, &score, 8000, 2, 0.2, seed)
+            };
+            let samples = render(1);
+            assert_eq!(samples.len(), 2 * (0.25 * 8000.0) as usize);
+            assert!(samples.iter().any(|s| *s != 0.0), "{waveform:?}");
+            assert_eq!(samples, render(1), "{waveform:?}");
+            assert_ne!(samples, render(2), "{waveform:?}");
+        }
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Lock-free single-producer/single-consumer ring buffer.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+struct Inner<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// Index of the next slot to read. Only written by the consumer.
+    head: AtomicUsize,
+    /// Index of the next slot to write. Only written by the producer.
+    tail: AtomicUsize,
+}
+
+// SAFETY: A slot is only accessed by the producer before `tail` is advanced
+// past it, and only by the consumer after that and before `head` is advanced
+// past it, so no slot is ever shared.
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    #[inline(always)]
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index & self.mask].get()
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            // SAFETY: Slots between head and tail are initialized.
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Producer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+pub struct Consumer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// Creates a ring buffer holding at least `capacity` items.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+
+    let inner = Arc::new(Inner {
+        slots,
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            inner: Arc::clone(&inner),
+        },
+        Consumer { inner },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Appends `value`, handing it back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.inner.tail.load(Ordering::Relaxed);
+        let head = self.inner.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > self.inner.mask {
+            return Err(value);
+        }
+
+        // SAFETY: The slot is free, see `Inner`.
+        unsafe { (*self.inner.slot(tail)).write(value) };
+        self.inner
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Returns the oldest item without removing it.
+    #[inline(always)]
+    pub fn peek(&self) -> Option<&T> {
+        let head = self.inner.head.load(Ordering::Relaxed);
+        let tail = self.inner.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // SAFETY: The slot is initialized and owned by the consumer until
+        // `head` is advanced, which requires `&mut self`.
+        Some(unsafe { (*self.inner.slot(head)).assume_init_ref() })
+    }
+
+    #[inline(always)]
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.inner.head.load(Ordering::Relaxed);
+        let tail = self.inner.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // SAFETY: See `peek`.
+        let value = unsafe { (*self.inner.slot(head)).assume_init_read() };
+        self.inner
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peeks_and_pops_in_order() {
+        let (mut producer, mut consumer) = channel(4);
+        assert_eq!(consumer.peek(), None);
+        for i in 0..3 {
+            producer.push(i).unwrap();
+        }
+
+        for i in 0..3 {
+            assert_eq!(consumer.peek(), Some(&i));
+            assert_eq!(consumer.peek(), Some(&i));
+            assert_eq!(consumer.pop(), Some(i));
+        }
+        assert_eq!(consumer.peek(), None);
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn push_fails_when_full() {
+        // Rounded up to 4.
+        let (mut producer, mut consumer) = channel(3);
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+
+        assert_eq!(consumer.pop(), Some(0));
+        producer.push(4).unwrap();
+        assert_eq!(producer.push(5), Err(5));
+    }
+
+    #[test]
+    fn wraps_around() {
+        let (mut producer, mut consumer) = channel(4);
+        for round in 0..10 {
+            for i in 0..3 {
+                producer.push(round * 3 + i).unwrap();
+            }
+            for i in 0..3 {
+                assert_eq!(consumer.pop(), Some(round * 3 + i));
+            }
+        }
+    }
+
+    #[test]
+    fn drops_pending_items() {
+        let item = Arc::new(());
+        let (mut producer, mut consumer) = channel(4);
+        for _ in 0..3 {
+            producer.push(Arc::clone(&item)).unwrap();
+        }
+        drop(consumer.pop());
+        assert_eq!(Arc::strong_count(&item), 3);
+
+        drop(producer);
+        assert_eq!(Arc::strong_count(&item), 3);
+        drop(consumer);
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn passes_items_between_threads() {
+        const COUNT: usize = 100_000;
+        let (mut producer, mut consumer) = channel(16);
+        let thread = std::thread::spawn(move || {
+            for i in 0..COUNT {
+                while producer.push(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match consumer.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        thread.join().unwrap();
+    }
+}
@@ -15,356 +15,475 @@
  * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
 use crate::{
-    atomicf::AtomicF32,
-    envelope::ADSR,
-    keyboard::{Key, Osc},
-    oscilator::Oscilator,
+    engine::Engine,
+    event::{SharedQueueSlot, TimedEvent},
+    params::Params,
+    ringbuf::Consumer,
 };
 
-struct PhaseStore {
-    phases: [f32; 12],
-}
+/// Hosts tried, in order, when the selected output can't be opened.
+const FALLBACK_HOSTS: [cpal::HostId; 2] = [cpal::HostId::Jack, cpal::HostId::Alsa];
 
-impl PhaseStore {
-    pub fn new() -> Self {
-        Self { phases: [0.0; 12] }
-    }
+/// Looks up a compiled-in host by its display name.
+pub fn host_by_name(name: &str) -> Option<cpal::HostId> {
+    cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name() == name)
+}
 
-    #[inline(always)]
-    fn get_phase_index(&self, key: Key) -> usize {
-        match key {
-            Key::C4 => 0,
-            Key::CS => 1,
-            Key::D4 => 2,
-            Key::DS => 3,
-            Key::E4 => 4,
-            Key::F4 => 5,
-            Key::FS => 6,
-            Key::G4 => 7,
-            Key::GS => 8,
-            Key::A4 => 9,
-            Key::AS => 10,
-            Key::B4 => 11,
-        }
-    }
+/// Names of the output devices of `host`. Empty if the host is unavailable.
+pub fn output_devices(host: cpal::HostId) -> Vec<String> {
+    let Ok(host) = cpal::host_from_id(host) else {
+        return Vec::new();
+    };
+    let Ok(devices) = host.output_devices() else {
+        return Vec::new();
+    };
 
-    #[inline(always)]
-    pub fn get_phase(&mut self, key: Key) -> &mut f32 {
-        &mut self.phases[self.get_phase_index(key)]
-    }
+    devices.filter_map(|device| device.name().ok()).collect()
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-enum KeyState {
-    Pressed,
-    Decay,
-    Sustain,
-    Released,
+/// Sample rates offered when the device supports them.
+const COMMON_SAMPLE_RATES: [u32; 7] = [22050, 44100, 48000, 88200, 96000, 176400, 192000];
+
+/// Requested stream configuration. `None` lets the device decide.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct StreamSettings {
+    pub sample_rate: Option<u32>,
+    /// Buffer size in frames.
+    pub buffer_size: Option<u32>,
+    pub channels: Option<u16>,
 }
 
-#[derive(Clone, Copy)]
-struct TrackElement {
-    pub state: KeyState,
-    pub amplitude: f32,
-    pub position: f32,
-    pub t_amplitude: f32,
+/// Stream configurations supported by an output device with f32 samples.
+#[derive(Clone, Default, Debug)]
+pub struct Capabilities {
+    pub sample_rates: Vec<u32>,
+    /// Smallest and largest buffer size in frames, if the device reports it.
+    pub buffer_sizes: Option<(u32, u32)>,
+    pub channels: Vec<u16>,
 }
 
-impl TrackElement {
-    #[inline(always)]
-    pub fn press(&mut self) {
-        if self.state == KeyState::Released {
-            self.state = KeyState::Pressed;
-            self.position = 0.0;
-            self.t_amplitude = self.amplitude;
+impl Capabilities {
+    fn new(configs: &[cpal::SupportedStreamConfigRange]) -> Self {
+        let mut caps = Self::default();
+        for config in configs {
+            for rate in COMMON_SAMPLE_RATES {
+                if (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&rate)
+                    && !caps.sample_rates.contains(&rate)
+                {
+                    caps.sample_rates.push(rate);
+                }
+            }
+            if !caps.channels.contains(&config.channels()) {
+                caps.channels.push(config.channels());
+            }
+            if let cpal::SupportedBufferSize::Range { min, max } = *config.buffer_size() {
+                caps.buffer_sizes = Some(match caps.buffer_sizes {
+                    Some((lo, hi)) => (lo.min(min), hi.max(max)),
+                    None => (min, max),
+                });
+            }
         }
+        caps.sample_rates.sort_unstable();
+        caps.channels.sort_unstable();
+        caps
     }
+}
 
-    #[inline(always)]
-    pub fn release(&mut self) {
-        if self.state != KeyState::Released {
-            self.state = KeyState::Released;
-            self.position = 0.0;
-            self.t_amplitude = self.amplitude;
-        }
-    }
+/// How often a lost output is reopened.
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a stream may go without rendering after a backend error before
+/// it is considered lost. Longer than any buffer.
+const STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Stream errors queued for the GUI before further ones are dropped.
+const ERROR_QUEUE_CAPACITY: usize = 16;
+
+#[derive(Debug)]
+pub enum SynthesizerError {
+    HostUnavailable(cpal::HostId),
+    Devices(cpal::DevicesError),
+    DeviceNotFound(String),
+    NoDefaultDevice,
+    DeviceName(cpal::DeviceNameError),
+    SupportedConfigs(cpal::SupportedStreamConfigsError),
+    NoMatchingConfig(StreamSettings),
+    BufferSize {
+        min: u32,
+        max: u32,
+    },
+    BuildStream(cpal::BuildStreamError),
+    PlayStream(cpal::PlayStreamError),
+    /// Reported by a running stream.
+    Stream(cpal::StreamError),
+    /// Every output that was tried failed.
+    NoOutput(Vec<(cpal::HostId, SynthesizerError)>),
+}
 
-    #[inline(always)]
-    pub fn tick(&mut self, sample_rate: f32, adsr: &ADSR) {
-        match self.state {
-            KeyState::Pressed => {
-                self.position += 1.0;
-                if self.position < sample_rate * adsr.attack {
-                    self.amplitude += (1.0 - self.t_amplitude) / (sample_rate * adsr.attack);
-                    return;
+impl fmt::Display for SynthesizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HostUnavailable(host) => write!(f, "{} is not available", host.name()),
+            Self::Devices(err) => write!(f, "failed to list devices: {err}"),
+            Self::DeviceNotFound(name) => write!(f, "no output device named \"{name}\""),
+            Self::NoDefaultDevice => write!(f, "no default output device"),
+            Self::DeviceName(err) => write!(f, "failed to get device name: {err}"),
+            Self::SupportedConfigs(err) => write!(f, "failed to query device: {err}"),
+            Self::NoMatchingConfig(settings) => {
+                match settings.channels {
+                    Some(channels) => write!(f, "no {channels} channel output configuration")?,
+                    None => write!(f, "no stereo output configuration")?,
                 }
-
-                self.amplitude = 1.0;
-                self.position = 0.0;
-                self.state = KeyState::Decay;
-            }
-            KeyState::Decay => {
-                self.position += 1.0;
-                if self.position < sample_rate * adsr.decay {
-                    self.amplitude -= (1.0 - adsr.sustain) / (sample_rate * adsr.decay);
-                    return;
+                if let Some(rate) = settings.sample_rate {
+                    write!(f, " at {rate} Hz")?;
                 }
-
-                self.amplitude = adsr.sustain;
-                self.state = KeyState::Sustain;
+                Ok(())
             }
-            KeyState::Sustain => {
-                self.amplitude = adsr.sustain;
+            Self::BufferSize { min, max } => {
+                write!(f, "buffer size must be between {min} and {max} frames")
             }
-            KeyState::Released => {
-                self.position += 1.0;
-                if self.position < (sample_rate * adsr.release) {
-                    self.amplitude -= self.t_amplitude / (sample_rate * adsr.release);
-                } else {
-                    self.amplitude = 0.0;
+            Self::BuildStream(err) => write!(f, "failed to open stream: {err}"),
+            Self::PlayStream(err) => write!(f, "failed to start stream: {err}"),
+            Self::Stream(err) => write!(f, "stream error: {err}"),
+            Self::NoOutput(attempts) => {
+                for (index, (host, err)) in attempts.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}: {err}", host.name())?;
                 }
+                Ok(())
             }
         }
     }
 }
 
-impl Default for TrackElement {
-    fn default() -> Self {
-        Self {
-            state: KeyState::Released,
-            amplitude: 0.0,
-            position: 0.0,
-            t_amplitude: 0.0,
+impl Error for SynthesizerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Devices(err) => Some(err),
+            Self::DeviceName(err) => Some(err),
+            Self::SupportedConfigs(err) => Some(err),
+            Self::BuildStream(err) => Some(err),
+            Self::PlayStream(err) => Some(err),
+            Self::Stream(err) => Some(err),
+            _ => None,
         }
     }
 }
 
-struct KeyAmplitudeTracker {
-    sample_rate: f32,
-    keys: [TrackElement; 12],
-    pub adsr: ADSR,
-}
-
-impl KeyAmplitudeTracker {
-    pub fn new(
-        sample_rate: f32,
-        attack_a: Arc<AtomicF32>,
-        decay_a: Arc<AtomicF32>,
-        sustain_a: Arc<AtomicF32>,
-        release_a: Arc<AtomicF32>,
-    ) -> Self {
-        Self {
-            sample_rate,
-            keys: [TrackElement::default(); 12],
-            adsr: ADSR::new(attack_a, decay_a, sustain_a, release_a),
-        }
+impl SynthesizerError {
+    /// True if the error alone means the output is gone and has to be
+    /// reopened. Backend specific errors are also reported by streams that
+    /// keep running, [`Synthesizer::poll`] only treats them as fatal once the
+    /// stream stops rendering. That is how JACK reports a server shutdown.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Stream(cpal::StreamError::DeviceNotAvailable))
     }
+}
 
-    #[inline(always)]
-    pub fn update(&mut self, keys: usize) {
-        self.adsr.update();
+/// Description of the running output stream.
+pub struct OutputInfo {
+    pub host: cpal::HostId,
+    pub device: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub capabilities: Capabilities,
+}
 
-        let mut mask = 0b1;
-        for i in 0..12 {
-            if (keys & mask) > 0 {
-                self.keys[i].press();
-            } else {
-                self.keys[i].release();
-            }
-            mask <<= 1;
-        }
-    }
+struct Output {
+    info: OutputInfo,
+    settings: StreamSettings,
+    /// Frames in the last buffer the audio thread rendered.
+    buffer_frames: Arc<AtomicUsize>,
+    /// Buffers rendered so far.
+    buffers: Arc<AtomicU64>,
+    /// Buffers rendered and time at the last backend error, until the stream
+    /// renders again.
+    stalled: Option<(u64, Instant)>,
+    _host: cpal::Host,
+    _device: cpal::Device,
+    _stream: cpal::Stream,
+}
 
-    #[inline(always)]
-    pub fn tick(&mut self) -> &[TrackElement; 12] {
-        for k in self.keys.iter_mut() {
-            k.tick(self.sample_rate, &self.adsr);
-        }
-        &self.keys
-    }
+/// An output that failed while running, waiting to be reopened.
+struct LostOutput {
+    host: cpal::HostId,
+    device: String,
+    settings: StreamSettings,
+    next_attempt: Instant,
 }
 
-struct Engine {
-    sample_rate: f32,
-    phases: PhaseStore,
-    key_tracker: KeyAmplitudeTracker,
-    active_keys: Arc<AtomicUsize>,
-    gain_a: Arc<AtomicF32>,
-    osc1: Oscilator,
-    osc2: Oscilator,
-    osc3: Oscilator,
+pub struct Synthesizer {
+    // Only locked by the GUI while no stream is running, so the audio thread
+    // never waits for it.
+    engine: Arc<Mutex<Engine>>,
+    output: Option<Output>,
+    lost: Option<LostOutput>,
+    /// Increased for every stream so errors from old streams can be ignored.
+    generation: u64,
+    errors_tx: SyncSender<(u64, cpal::StreamError)>,
+    errors_rx: Receiver<(u64, cpal::StreamError)>,
 }
 
-impl Engine {
-    pub fn new(
-        sample_rate: f32,
-        attack_a: Arc<AtomicF32>,
-        decay_a: Arc<AtomicF32>,
-        sustain_a: Arc<AtomicF32>,
-        release_a: Arc<AtomicF32>,
-        active_keys: Arc<AtomicUsize>,
-        gain_a: Arc<AtomicF32>,
-        osc1: Osc,
-        osc2: Osc,
-        osc3: Osc,
-    ) -> Self {
+impl Synthesizer {
+    /// Creates the engine without any audio output, see [`Synthesizer::open`].
+    pub fn new(params: Params, events: Vec<Consumer<TimedEvent>>, clock: Arc<AtomicU64>) -> Self {
+        let (errors_tx, errors_rx) = mpsc::sync_channel(ERROR_QUEUE_CAPACITY);
         Self {
-            sample_rate,
-            phases: PhaseStore::new(),
-            key_tracker: KeyAmplitudeTracker::new(
-                sample_rate,
-                attack_a,
-                decay_a,
-                sustain_a,
-                release_a,
-            ),
-            active_keys,
-            gain_a,
-            osc1: Oscilator::new(osc1.waveform, osc1.active, osc1.gain),
-            osc2: Oscilator::new(osc2.waveform, osc2.active, osc2.gain),
-            osc3: Oscilator::new(osc3.waveform, osc3.active, osc3.gain),
+            engine: Arc::new(Mutex::new(Engine::new(44100.0, params, events, clock))),
+            output: None,
+            lost: None,
+            generation: 0,
+            errors_tx,
+            errors_rx,
         }
     }
 
-    #[inline(always)]
-    pub fn on_buffer(&mut self, buffer: &mut [f32], channels: usize) {
-        self.key_tracker
-            .update(self.active_keys.load(Ordering::Acquire));
-
-        let fgain = self.gain_a.load(Ordering::Acquire);
-
-        self.osc1.update();
-        self.osc2.update();
-        self.osc3.update();
+    /// Adds an event source whose queue can be replaced while a stream runs,
+    /// see [`Engine::share_queue`]. Call it before opening a stream.
+    pub fn share_queue(&self, slot: SharedQueueSlot) {
+        self.engine.lock().unwrap().share_queue(slot);
+    }
 
-        for sample_frame in buffer.chunks_mut(channels) {
-            let amps = self.key_tracker.tick();
-            let mut sum_amps: f32 = 0.0;
+    /// The running output stream, if any.
+    pub fn output(&self) -> Option<&OutputInfo> {
+        self.output.as_ref().map(|output| &output.info)
+    }
 
-            let mut sample_w: f32 = 0.0;
-            for (index, element) in amps.iter().enumerate() {
-                if element.amplitude == 0.0 {
-                    continue;
-                }
+    /// True while a lost output is being reopened by [`Synthesizer::poll`].
+    pub fn is_recovering(&self) -> bool {
+        self.lost.is_some()
+    }
 
-                let key = Key::from_zero_index(index);
-                let freq = key.freq();
+    /// Output latency of one buffer in seconds, once the stream has rendered
+    /// its first buffer.
+    pub fn latency(&self) -> Option<f32> {
+        let output = self.output.as_ref()?;
+        match output.buffer_frames.load(Ordering::Acquire) {
+            0 => None,
+            frames => Some(frames as f32 / output.info.sample_rate as f32),
+        }
+    }
 
-                let phase = self.phases.get_phase(key);
-                let adam = *phase;
+    /// Opens `device` on `host`, or the host's default device if `device` is
+    /// `None`. If that fails the default devices of the fallback hosts are
+    /// tried in turn.
+    pub fn open(
+        &mut self,
+        host: Option<cpal::HostId>,
+        device: Option<&str>,
+        settings: StreamSettings,
+    ) -> Result<(), SynthesizerError> {
+        self.close();
+
+        let mut candidates = Vec::new();
+        if let Some(host) = host {
+            candidates.push((host, device));
+        }
+        for fallback in FALLBACK_HOSTS {
+            if !candidates.contains(&(fallback, None)) {
+                candidates.push((fallback, None));
+            }
+        }
 
-                if self.osc1.active {
-                    sum_amps += element.amplitude * self.osc1.gain;
-                    sample_w += element.amplitude * self.osc1.tick(adam);
+        let mut attempts = Vec::new();
+        for (host, device) in candidates {
+            match self.open_output(host, device, settings) {
+                Ok(output) => {
+                    self.output = Some(output);
+                    return Ok(());
                 }
+                Err(err) => attempts.push((host, err)),
+            }
+        }
 
-                if self.osc2.active {
-                    sum_amps += element.amplitude * self.osc2.gain;
-                    sample_w += element.amplitude * self.osc2.tick(adam);
-                }
+        Err(SynthesizerError::NoOutput(attempts))
+    }
 
-                if self.osc3.active {
-                    sum_amps += element.amplitude * self.osc3.gain;
-                    sample_w += element.amplitude * self.osc3.tick(adam);
-                }
+    /// Stops the stream, leaving the engine intact.
+    pub fn close(&mut self) {
+        self.output = None;
+        self.lost = None;
+    }
 
-                *phase += freq / self.sample_rate;
-                if *phase > 1.0 {
-                    *phase -= 1.0;
-                }
+    /// Collects errors reported by the stream since the last call. If the
+    /// output was lost it is closed and reopened with the same device and
+    /// settings once per [`RECOVERY_INTERVAL`], without falling back to other
+    /// hosts. Returns the most recent error, if any.
+    pub fn poll(&mut self) -> Option<SynthesizerError> {
+        let mut last = None;
+        while let Ok((generation, err)) = self.errors_rx.try_recv() {
+            if generation == self.generation {
+                last = Some(SynthesizerError::Stream(err));
             }
+        }
 
-            sample_w *= 1.0 / 1.0f32.max(sum_amps);
+        let mut fatal = last.as_ref().is_some_and(SynthesizerError::is_fatal);
+        if let Some(output) = self.output.as_mut() {
+            let buffers = output.buffers.load(Ordering::Acquire);
+            if last.is_some() && output.stalled.is_none() {
+                output.stalled = Some((buffers, Instant::now()));
+            }
+            match output.stalled {
+                Some((before, _)) if buffers != before => output.stalled = None,
+                Some((_, since)) if since.elapsed() >= STALL_TIMEOUT => fatal = true,
+                _ => {}
+            }
+        }
 
-            let the_sample = fgain * sample_w;
+        if fatal {
+            if let Some(output) = self.output.take() {
+                self.lost = Some(LostOutput {
+                    host: output.info.host,
+                    device: output.info.device,
+                    settings: output.settings,
+                    next_attempt: Instant::now(),
+                });
+            }
+        }
 
-            for sample in sample_frame.iter_mut() {
-                *sample = the_sample;
+        let lost = self.lost.as_mut()?;
+        if Instant::now() < lost.next_attempt {
+            return last;
+        }
+        lost.next_attempt = Instant::now() + RECOVERY_INTERVAL;
+
+        let (host, device, settings) = (lost.host, lost.device.clone(), lost.settings);
+        match self.open_output(host, Some(&device), settings) {
+            Ok(output) => {
+                self.output = Some(output);
+                self.lost = None;
+                last
             }
+            Err(err) => Some(err),
         }
     }
-}
 
-pub struct Synthesizer {
-    _host: cpal::Host,
-    _device: cpal::Device,
-    _supported_config: cpal::SupportedStreamConfig,
-    _stream: cpal::Stream,
-}
+    fn open_output(
+        &mut self,
+        host_id: cpal::HostId,
+        device: Option<&str>,
+        settings: StreamSettings,
+    ) -> Result<Output, SynthesizerError> {
+        if !cpal::available_hosts().contains(&host_id) {
+            return Err(SynthesizerError::HostUnavailable(host_id));
+        }
+        let host =
+            cpal::host_from_id(host_id).map_err(|_| SynthesizerError::HostUnavailable(host_id))?;
+
+        let device = match device {
+            Some(name) => host
+                .output_devices()
+                .map_err(SynthesizerError::Devices)?
+                .find(|d| d.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| SynthesizerError::DeviceNotFound(name.to_string()))?,
+            None => host
+                .default_output_device()
+                .ok_or(SynthesizerError::NoDefaultDevice)?,
+        };
+        let device_name = device.name().map_err(SynthesizerError::DeviceName)?;
+
+        // The engine renders f32, so only those configurations are offered.
+        let configs: Vec<_> = device
+            .supported_output_configs()
+            .map_err(SynthesizerError::SupportedConfigs)?
+            .filter(|c| c.sample_format() == cpal::SampleFormat::F32)
+            .collect();
+        let capabilities = Capabilities::new(&configs);
+
+        let supported_config = configs
+            .into_iter()
+            .filter(|c| match settings.channels {
+                Some(channels) => c.channels() == channels,
+                None => c.channels() >= 2,
+            })
+            .find_map(|c| match settings.sample_rate {
+                Some(rate) => c.try_with_sample_rate(cpal::SampleRate(rate)),
+                None => Some(c.with_max_sample_rate()),
+            })
+            .ok_or(SynthesizerError::NoMatchingConfig(settings))?;
+
+        let mut config = supported_config.config();
+        if let Some(frames) = settings.buffer_size {
+            if let cpal::SupportedBufferSize::Range { min, max } = *supported_config.buffer_size() {
+                if !(min..=max).contains(&frames) {
+                    return Err(SynthesizerError::BufferSize { min, max });
+                }
+            }
+            config.buffer_size = cpal::BufferSize::Fixed(frames);
+        }
 
-impl Synthesizer {
-    pub fn new(
-        gain: Arc<AtomicF32>,
-        active_keys: Arc<AtomicUsize>,
-        attack: Arc<AtomicF32>,
-        decay: Arc<AtomicF32>,
-        sustain: Arc<AtomicF32>,
-        release: Arc<AtomicF32>,
-        osc1: Osc,
-        osc2: Osc,
-        osc3: Osc,
-    ) -> Self {
-        let host = cpal::host_from_id(
-            cpal::available_hosts()
-                .into_iter()
-                .find(|id| *id == cpal::HostId::Jack)
-                .unwrap(),
-        )
-        .unwrap();
-        let device = host.default_output_device().unwrap();
-        let supported_configs_range = device.supported_output_configs().unwrap();
-
-        let supported_config = supported_configs_range
-            .filter(|c| c.channels() >= 2)
-            .next()
-            .unwrap()
-            .with_max_sample_rate();
-
-        let sample_rate = supported_config.sample_rate().0 as f32;
-        let mut synth = Engine::new(
-            sample_rate,
-            attack,
-            decay,
-            sustain,
-            release,
-            active_keys,
-            gain,
-            osc1,
-            osc2,
-            osc3,
-        );
-        let channels = supported_config.channels() as usize;
-
-        println!("[DEBUG] Channels:    {channels}");
-        println!("[DEBUG] Sample rate: {sample_rate}");
-        println!("[DEBUG] Buffer size: {:?}", supported_config.buffer_size());
+        let sample_rate = config.sample_rate.0;
+        let channels = config.channels as usize;
+
+        self.engine
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .set_sample_rate(sample_rate as f32);
+
+        self.generation += 1;
+        let generation = self.generation;
+        let errors_tx = self.errors_tx.clone();
 
+        let engine = Arc::clone(&self.engine);
+        let buffer_frames = Arc::new(AtomicUsize::new(0));
+        let buffer_frames_clone = Arc::clone(&buffer_frames);
+        let buffers = Arc::new(AtomicU64::new(0));
+        let buffers_clone = Arc::clone(&buffers);
         let stream = device
             .build_output_stream(
-                &supported_config.config(),
+                &config,
                 move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
-                    synth.on_buffer(data, channels);
+                    buffer_frames_clone.store(data.len() / channels, Ordering::Release);
+                    buffers_clone.fetch_add(1, Ordering::Release);
+                    match engine.try_lock() {
+                        Ok(mut engine) => engine.process(data, channels),
+                        Err(_) => data.fill(0.0),
+                    }
+                },
+                move |err| {
+                    // Never block the stream, the GUI catches up on the next
+                    // error if the queue is full.
+                    let _ = errors_tx.try_send((generation, err));
                 },
-                move |_err| {},
                 None,
             )
-            .unwrap();
+            .map_err(SynthesizerError::BuildStream)?;
 
-        stream.play().unwrap();
+        stream.play().map_err(SynthesizerError::PlayStream)?;
 
-        Self {
+        Ok(Output {
+            info: OutputInfo {
+                host: host_id,
+                device: device_name,
+                sample_rate,
+                channels: config.channels,
+                capabilities,
+            },
+            settings,
+            buffer_frames,
+            buffers,
+            stalled: None,
             _host: host,
             _device: device,
-            _supported_config: supported_config,
             _stream: stream,
-        }
+        })
     }
 }
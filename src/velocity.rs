@@ -0,0 +1,97 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::atomicf::{AtomicF32, AtomicVelocityCurve};
+
+/// Steepness of the exponential and logarithmic curves.
+const CURVE_STEEPNESS: f32 = 4.0;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum VelocityCurve {
+    Linear = 1,
+    /// Soft notes get quieter, more room at the top of the range.
+    Exponential = 2,
+    /// Soft notes get louder, more room at the bottom of the range.
+    Logarithmic = 3,
+    /// Every note plays at full velocity.
+    Fixed = 4,
+}
+
+impl From<i32> for VelocityCurve {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Linear,
+            2 => Self::Exponential,
+            3 => Self::Logarithmic,
+            4 => Self::Fixed,
+            _ => panic!("Invalid velocity curve integer"),
+        }
+    }
+}
+
+impl VelocityCurve {
+    /// Maps a velocity in `0.0..=1.0` through the curve.
+    #[inline(always)]
+    pub fn apply(self, velocity: f32) -> f32 {
+        let velocity = velocity.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => velocity,
+            Self::Exponential => {
+                ((CURVE_STEEPNESS * velocity).exp() - 1.0) / (CURVE_STEEPNESS.exp() - 1.0)
+            }
+            Self::Logarithmic => {
+                (1.0 + (CURVE_STEEPNESS.exp() - 1.0) * velocity).ln() / CURVE_STEEPNESS
+            }
+            Self::Fixed => 1.0,
+        }
+    }
+}
+
+/// Velocity response, routed to the amplitude envelope.
+pub struct Velocity {
+    pub curve: VelocityCurve,
+    /// How much velocity affects amplitude. At 0.0 every note plays at full
+    /// level.
+    pub amount: f32,
+    curve_a: Arc<AtomicVelocityCurve>,
+    amount_a: Arc<AtomicF32>,
+}
+
+impl Velocity {
+    pub fn new(curve_a: Arc<AtomicVelocityCurve>, amount_a: Arc<AtomicF32>) -> Self {
+        Self {
+            curve: VelocityCurve::Linear,
+            amount: 1.0,
+            curve_a,
+            amount_a,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        self.curve = self.curve_a.load(Ordering::Acquire);
+        self.amount = self.amount_a.load(Ordering::Acquire);
+    }
+
+    /// Peak envelope level for a note played at `velocity`.
+    #[inline(always)]
+    pub fn amplitude(&self, velocity: f32) -> f32 {
+        1.0 - self.amount + self.amount * self.curve.apply(velocity)
+    }
+}
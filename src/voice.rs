@@ -0,0 +1,399 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{
+    atomicf::AtomicStealPolicy,
+    envelope::{KeyState, TrackElement, AMP_ENVELOPE, ENVELOPES},
+    filter::FilterState,
+    keyboard::Key,
+    lfo::{LfoState, LFOS},
+    modulation::Targets,
+    mseg::MsegState,
+    oscilator::OscState,
+};
+
+/// Upper bound for the configurable polyphony. Voices are preallocated so the
+/// audio thread never allocates.
+pub const MAX_VOICES: usize = 32;
+
+/// Which voice to take over when a note is played and every voice is in use.
+/// Voices that are already releasing are always preferred over held ones.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StealPolicy {
+    /// Steal the voice that was started first.
+    Oldest = 1,
+    /// Steal the voice with the lowest envelope amplitude.
+    Quietest = 2,
+    /// Retrigger a voice already playing the same note, otherwise steal the
+    /// oldest one.
+    SameNote = 3,
+    /// Low notes have priority, the highest sounding note is stolen.
+    LowNote = 4,
+    /// High notes have priority, the lowest sounding note is stolen.
+    HighNote = 5,
+}
+
+impl From<i32> for StealPolicy {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => Self::Oldest,
+            2 => Self::Quietest,
+            3 => Self::SameNote,
+            4 => Self::LowNote,
+            5 => Self::HighNote,
+            _ => panic!("Invalid steal policy integer"),
+        }
+    }
+}
+
+impl StealPolicy {
+    pub const ALL: [Self; 5] = [
+        Self::Oldest,
+        Self::Quietest,
+        Self::SameNote,
+        Self::LowNote,
+        Self::HighNote,
+    ];
+}
+
+#[derive(Clone, Copy)]
+pub struct Voice {
+    pub key: Key,
+    pub velocity: f32,
+    /// Polyphonic aftertouch.
+    pub pressure: f32,
+    /// Indexed by [`AMP_ENVELOPE`] and the other envelope indices.
+    pub envelopes: [TrackElement; ENVELOPES],
+    pub oscs: [OscState; 3],
+    pub filter: FilterState,
+    pub mseg: MsegState,
+    pub lfos: [LfoState; LFOS],
+    /// Modulation of the last sample. The LFOs are modulated from it, as
+    /// they are sources themselves.
+    pub targets: Targets,
+    active: bool,
+    /// Value of the pool's note counter when this voice was last started.
+    started: u64,
+}
+
+impl Voice {
+    #[inline(always)]
+    pub fn amp_envelope(&self) -> &TrackElement {
+        &self.envelopes[AMP_ENVELOPE]
+    }
+
+    #[inline(always)]
+    fn is_held(&self) -> bool {
+        self.active && self.amp_envelope().state != KeyState::Released
+    }
+
+    #[inline(always)]
+    fn release(&mut self) {
+        for envelope in self.envelopes.iter_mut() {
+            envelope.release();
+        }
+        self.mseg.release();
+    }
+}
+
+impl Default for Voice {
+    fn default() -> Self {
+        Self {
+            key: Key::C4,
+            velocity: 0.0,
+            pressure: 0.0,
+            envelopes: [TrackElement::default(); ENVELOPES],
+            oscs: [OscState::default(); 3],
+            filter: FilterState::default(),
+            mseg: MsegState::default(),
+            lfos: [LfoState::default(); LFOS],
+            targets: Targets::default(),
+            active: false,
+            started: 0,
+        }
+    }
+}
+
+pub struct VoicePool {
+    voices: [Voice; MAX_VOICES],
+    pub polyphony: usize,
+    pub policy: StealPolicy,
+    polyphony_a: Arc<AtomicUsize>,
+    policy_a: Arc<AtomicStealPolicy>,
+    counter: u64,
+}
+
+impl VoicePool {
+    pub fn new(polyphony_a: Arc<AtomicUsize>, policy_a: Arc<AtomicStealPolicy>) -> Self {
+        Self {
+            voices: [Voice::default(); MAX_VOICES],
+            polyphony: MAX_VOICES,
+            policy: StealPolicy::Oldest,
+            polyphony_a,
+            policy_a,
+            counter: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn update(&mut self) {
+        self.polyphony = self
+            .polyphony_a
+            .load(Ordering::Acquire)
+            .clamp(1, MAX_VOICES);
+        self.policy = self.policy_a.load(Ordering::Acquire);
+
+        // Voices above a lowered limit are left to fade out and are never
+        // allocated again.
+        for voice in self.voices[self.polyphony..].iter_mut() {
+            if voice.is_held() {
+                voice.release();
+            }
+        }
+    }
+
+    /// Starts `key`, ramping each envelope towards its entry in `levels`. Returns the voice
+    /// so the caller can set up the rest of its state, and whether it was
+    /// silent before. A voice that was still sounding keeps its phases to
+    /// avoid clicks.
+    pub fn note_on(
+        &mut self,
+        key: Key,
+        velocity: f32,
+        levels: [f32; ENVELOPES],
+    ) -> (&mut Voice, bool) {
+        self.counter += 1;
+
+        let index = match self.find_same_note(key) {
+            Some(index) if self.policy == StealPolicy::SameNote => index,
+            _ => match self.voices[..self.polyphony].iter().position(|v| !v.active) {
+                Some(index) => index,
+                None => self.steal(),
+            },
+        };
+
+        let voice = &mut self.voices[index];
+        let restarted = !voice.active;
+        voice.key = key;
+        voice.velocity = velocity;
+        voice.pressure = 0.0;
+        voice.active = true;
+        voice.started = self.counter;
+        for (envelope, level) in voice.envelopes.iter_mut().zip(levels) {
+            envelope.press(level);
+        }
+        (voice, restarted)
+    }
+
+    pub fn note_off(&mut self, key: Key) {
+        for voice in self.voices.iter_mut() {
+            if voice.is_held() && voice.key == key {
+                voice.release();
+            }
+        }
+    }
+
+    pub fn set_pressure(&mut self, key: Key, pressure: f32) {
+        for voice in self.voices.iter_mut() {
+            if voice.is_held() && voice.key == key {
+                voice.pressure = pressure;
+            }
+        }
+    }
+
+    /// Releases every held voice.
+    pub fn release_all(&mut self) {
+        for voice in self.voices.iter_mut() {
+            if voice.is_held() {
+                voice.release();
+            }
+        }
+    }
+
+    /// Silences every voice immediately.
+    pub fn kill_all(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.active = false;
+            voice.envelopes = [TrackElement::default(); ENVELOPES];
+        }
+    }
+
+    /// Frees voices whose release has finished.
+    #[inline(always)]
+    pub fn reap(&mut self) {
+        for voice in self.voices.iter_mut() {
+            if voice.active && voice.amp_envelope().is_finished() {
+                voice.active = false;
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Voice> {
+        self.voices.iter_mut().filter(|v| v.active)
+    }
+
+    fn find_same_note(&self, key: Key) -> Option<usize> {
+        self.voices[..self.polyphony]
+            .iter()
+            .position(|v| v.active && v.key == key)
+    }
+
+    fn steal(&self) -> usize {
+        let voices = &self.voices[..self.polyphony];
+        let releasing = voices.iter().any(|v| !v.is_held());
+
+        let candidates = voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !releasing || !v.is_held());
+
+        let chosen = match self.policy {
+            StealPolicy::Oldest | StealPolicy::SameNote => {
+                candidates.min_by_key(|(_, v)| v.started)
+            }
+            StealPolicy::Quietest => candidates.min_by(|(_, a), (_, b)| {
+                let (a, b) = (a.amp_envelope(), b.amp_envelope());
+                a.amplitude.total_cmp(&b.amplitude)
+            }),
+            StealPolicy::LowNote => candidates.max_by_key(|(_, v)| v.key),
+            StealPolicy::HighNote => candidates.min_by_key(|(_, v)| v.key),
+        };
+
+        chosen.map(|(index, _)| index).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(polyphony: usize, policy: StealPolicy) -> VoicePool {
+        let mut pool = VoicePool::new(
+            Arc::new(AtomicUsize::new(polyphony)),
+            Arc::new(AtomicStealPolicy::new(policy)),
+        );
+        pool.update();
+        pool
+    }
+
+    fn play(pool: &mut VoicePool, notes: &[u8]) {
+        for &note in notes {
+            pool.note_on(Key::new(note).unwrap(), 1.0, [1.0; ENVELOPES]);
+        }
+    }
+
+    /// Notes of the active voices, sorted.
+    fn sounding(pool: &mut VoicePool) -> Vec<u8> {
+        let mut notes: Vec<u8> = pool.iter_mut().map(|v| v.key.note()).collect();
+        notes.sort();
+        notes
+    }
+
+    #[test]
+    fn oldest_steals_the_first_started() {
+        let mut pool = pool(3, StealPolicy::Oldest);
+        play(&mut pool, &[60, 61, 62, 63]);
+        assert_eq!(sounding(&mut pool), [61, 62, 63]);
+    }
+
+    #[test]
+    fn quietest_steals_the_lowest_amplitude() {
+        let mut pool = pool(3, StealPolicy::Quietest);
+        play(&mut pool, &[60, 61, 62]);
+        for voice in pool.iter_mut() {
+            voice.envelopes[AMP_ENVELOPE].amplitude = match voice.key.note() {
+                61 => 0.1,
+                _ => 0.8,
+            };
+        }
+        play(&mut pool, &[63]);
+        assert_eq!(sounding(&mut pool), [60, 62, 63]);
+    }
+
+    #[test]
+    fn same_note_retriggers_its_voice() {
+        let mut pool = pool(3, StealPolicy::SameNote);
+        play(&mut pool, &[60, 61, 62]);
+        let (voice, restarted) = pool.note_on(Key::new(60).unwrap(), 0.5, [0.5; ENVELOPES]);
+        assert_eq!(voice.key.note(), 60);
+        assert!(!restarted);
+        assert_eq!(sounding(&mut pool), [60, 61, 62]);
+
+        // A new note falls back to the oldest, which is now 61.
+        play(&mut pool, &[63]);
+        assert_eq!(sounding(&mut pool), [60, 62, 63]);
+    }
+
+    #[test]
+    fn low_note_steals_the_highest() {
+        let mut pool = pool(3, StealPolicy::LowNote);
+        play(&mut pool, &[60, 72, 48, 50]);
+        assert_eq!(sounding(&mut pool), [48, 50, 60]);
+    }
+
+    #[test]
+    fn high_note_steals_the_lowest() {
+        let mut pool = pool(3, StealPolicy::HighNote);
+        play(&mut pool, &[60, 72, 48, 50]);
+        assert_eq!(sounding(&mut pool), [50, 60, 72]);
+    }
+
+    #[test]
+    fn releasing_voices_are_stolen_first() {
+        for policy in StealPolicy::ALL {
+            let mut pool = pool(3, policy);
+            play(&mut pool, &[48, 60, 72]);
+            for voice in pool.iter_mut() {
+                voice.envelopes[AMP_ENVELOPE].amplitude = 1.0;
+            }
+            // Every policy would pick another voice if 60 were held.
+            pool.note_off(Key::new(60).unwrap());
+            play(&mut pool, &[84]);
+            assert_eq!(sounding(&mut pool), [48, 72, 84], "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn lowering_polyphony_releases_the_voices_above_it() {
+        let mut pool = pool(4, StealPolicy::Oldest);
+        play(&mut pool, &[60, 61, 62, 63]);
+        for voice in pool.iter_mut() {
+            voice.envelopes[AMP_ENVELOPE].amplitude = 1.0;
+        }
+
+        pool.polyphony_a.store(2, Ordering::Release);
+        pool.update();
+        for voice in pool.iter_mut() {
+            assert_eq!(voice.is_held(), voice.key.note() < 62);
+        }
+
+        // Only the first two voices are used, the others fade out.
+        play(&mut pool, &[64]);
+        assert_eq!(sounding(&mut pool), [61, 62, 63, 64]);
+        for voice in pool.iter_mut() {
+            voice.envelopes[AMP_ENVELOPE].amplitude = 0.0;
+        }
+        pool.reap();
+        assert_eq!(sounding(&mut pool), [61, 64]);
+    }
+}
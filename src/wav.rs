@@ -0,0 +1,324 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Minimal WAV reader and writer.
+
+use std::io::{self, Read, Write};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SampleFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl SampleFormat {
+    fn bits(self) -> u16 {
+        match self {
+            Self::Int16 => 16,
+            Self::Int24 => 24,
+            Self::Float32 => 32,
+        }
+    }
+
+    fn format_tag(self) -> u16 {
+        match self {
+            Self::Int16 | Self::Int24 => 1,
+            Self::Float32 => 3,
+        }
+    }
+}
+
+fn too_large(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+/// Writes interleaved `samples` as a WAV file. Integer formats clip samples
+/// outside `-1.0..=1.0`.
+pub fn write<W: Write>(
+    mut writer: W,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    format: SampleFormat,
+) -> io::Result<()> {
+    let block_align = u16::try_from(channels as u32 * format.bits() as u32 / 8)
+        .map_err(|_| too_large("too many channels for WAV"))?;
+    let byte_rate = sample_rate
+        .checked_mul(block_align as u32)
+        .ok_or_else(|| too_large("sample rate too high for WAV"))?;
+    // Non-PCM formats need the cbSize field and a fact chunk.
+    let pcm = format.format_tag() == 1;
+    let (fmt_len, fact_len) = if pcm { (16, 0) } else { (18, 12) };
+    let header_len = 4 + (8 + fmt_len) + fact_len + 8;
+    // Chunks are padded to an even length, the pad is not part of the data.
+    let data_len = u32::try_from(samples.len() * (format.bits() / 8) as usize)
+        .ok()
+        .filter(|len| *len < u32::MAX - header_len)
+        .ok_or_else(|| too_large("too many samples for WAV"))?;
+    let pad = data_len % 2;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(header_len + data_len + pad).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_len.to_le_bytes())?;
+    writer.write_all(&format.format_tag().to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&format.bits().to_le_bytes())?;
+    if !pcm {
+        writer.write_all(&0u16.to_le_bytes())?;
+
+        let frames = (samples.len() / channels.max(1) as usize) as u32;
+        writer.write_all(b"fact")?;
+        writer.write_all(&4u32.to_le_bytes())?;
+        writer.write_all(&frames.to_le_bytes())?;
+    }
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    for &sample in samples {
+        match format {
+            SampleFormat::Int16 => {
+                let v = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                writer.write_all(&v.to_le_bytes())?;
+            }
+            SampleFormat::Int24 => {
+                let v = (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+                writer.write_all(&v.to_le_bytes()[..3])?;
+            }
+            SampleFormat::Float32 => writer.write_all(&sample.to_le_bytes())?,
+        }
+    }
+    if pad == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    writer.flush()
+}
+
+/// Decoded contents of a WAV file.
+pub struct Wav {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved samples in `-1.0..=1.0`.
+    pub samples: Vec<f32>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Sample encodings accepted by [`read`].
+#[derive(Clone, Copy)]
+enum Encoding {
+    Int16,
+    Int24,
+    Int32,
+    Float32,
+}
+
+impl Encoding {
+    fn decode(self, data: &[u8]) -> Vec<f32> {
+        match self {
+            Self::Int16 => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+            Self::Int24 => data
+                .chunks_exact(3)
+                .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0)
+                .collect(),
+            Self::Int32 => data
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+                .collect(),
+            Self::Float32 => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        }
+    }
+}
+
+/// Reads a WAV file with 16, 24 or 32-bit integer, or 32-bit float samples.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Wav> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk)?;
+        let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        // The length is not trusted for the allocation, a corrupt header
+        // could claim up to 4 GiB.
+        let mut body = Vec::new();
+        reader.by_ref().take(len as u64).read_to_end(&mut body)?;
+        if body.len() != len {
+            return Err(invalid("truncated chunk"));
+        }
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                if body.len() < 16 {
+                    return Err(invalid("truncated fmt chunk"));
+                }
+                let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                let mut tag = u16_at(0);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real tag in the sub-format.
+                if tag == 0xfffe && body.len() >= 26 {
+                    tag = u16_at(24);
+                }
+                let channels = u16_at(2);
+                if channels == 0 {
+                    return Err(invalid("no channels"));
+                }
+                let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let encoding = match (tag, u16_at(14)) {
+                    (1, 16) => Encoding::Int16,
+                    (1, 24) => Encoding::Int24,
+                    (1, 32) => Encoding::Int32,
+                    (3, 32) => Encoding::Float32,
+                    _ => return Err(invalid("unsupported sample format")),
+                };
+                format = Some((channels, sample_rate, encoding));
+            }
+            b"data" => {
+                let (channels, sample_rate, encoding) =
+                    format.ok_or_else(|| invalid("data before fmt chunk"))?;
+                return Ok(Wav {
+                    channels,
+                    sample_rate,
+                    samples: encoding.decode(&body),
+                });
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even length. The pad is only read when
+        // another chunk follows, many writers leave it off the last one.
+        if len % 2 == 1 {
+            reader.read_exact(&mut [0])?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: SampleFormat, channels: u16, frames: usize) -> (Vec<u8>, Wav) {
+        let samples: Vec<f32> = (0..frames * channels as usize)
+            .map(|i| (i as f32 * 0.37).sin() * 0.9)
+            .collect();
+        let mut file = Vec::new();
+        write(&mut file, &samples, channels, 44100, format).unwrap();
+        let wav = read(file.as_slice()).unwrap();
+
+        assert_eq!(wav.channels, channels);
+        assert_eq!(wav.sample_rate, 44100);
+        assert_eq!(wav.samples.len(), samples.len());
+        let tolerance = match format {
+            SampleFormat::Int16 => 1.0 / 16384.0,
+            SampleFormat::Int24 => 1.0 / 4_194_304.0,
+            SampleFormat::Float32 => 0.0,
+        };
+        for (read, written) in wav.samples.iter().zip(&samples) {
+            assert!((read - written).abs() <= tolerance, "{read} != {written}");
+        }
+        (file, wav)
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for format in [
+            SampleFormat::Int16,
+            SampleFormat::Int24,
+            SampleFormat::Float32,
+        ] {
+            for (channels, frames) in [(1, 100), (2, 101), (1, 101)] {
+                round_trip(format, channels, frames);
+            }
+        }
+    }
+
+    #[test]
+    fn pads_odd_data_chunk() {
+        let (file, _) = round_trip(SampleFormat::Int24, 1, 101);
+        assert_eq!(file.len() % 2, 0);
+        let riff_len = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        assert_eq!(riff_len, file.len() - 8);
+    }
+
+    #[test]
+    fn reads_unpadded_last_chunk() {
+        let (mut file, wav) = round_trip(SampleFormat::Int24, 1, 101);
+        file.pop();
+        assert_eq!(read(file.as_slice()).unwrap().samples, wav.samples);
+    }
+
+    #[test]
+    fn skips_padded_chunks() {
+        let (file, wav) = round_trip(SampleFormat::Int16, 2, 10);
+        // An odd chunk with its pad byte between fmt and data.
+        let mut padded = file[..36].to_vec();
+        padded.extend_from_slice(b"LIST");
+        padded.extend_from_slice(&3u32.to_le_bytes());
+        padded.extend_from_slice(&[1, 2, 3, 0]);
+        padded.extend_from_slice(&file[36..]);
+        assert_eq!(read(padded.as_slice()).unwrap().samples, wav.samples);
+    }
+
+    #[test]
+    fn writes_fact_chunk_for_float() {
+        let (file, _) = round_trip(SampleFormat::Float32, 2, 10);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]);
+        assert_eq!(&file[12..16], b"fmt ");
+        assert_eq!(u32_at(16), 18);
+        // cbSize.
+        assert_eq!(&file[36..38], &[0, 0]);
+        assert_eq!(&file[38..42], b"fact");
+        assert_eq!(u32_at(42), 4);
+        assert_eq!(u32_at(46), 10);
+        assert_eq!(&file[50..54], b"data");
+        assert_eq!(u32_at(4) as usize, file.len() - 8);
+    }
+
+    #[test]
+    fn rejects_chunk_longer_than_file() {
+        let (mut file, _) = round_trip(SampleFormat::Int16, 1, 10);
+        // The data chunk claims 4 GiB.
+        file[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read(file.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_too_many_channels() {
+        let err = write(Vec::new(), &[], u16::MAX, 44100, SampleFormat::Float32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -15,12 +15,17 @@
  * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
  */
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Waveform {
     Sin = 1,
     Square = 2,
     Saw = 3,
     Triangle = 4,
+    /// The oscillator's wavetable.
+    Wavetable = 5,
+    WhiteNoise = 6,
+    PinkNoise = 7,
+    BrownNoise = 8,
 }
 
 impl From<i32> for Waveform {
@@ -30,6 +35,10 @@ impl From<i32> for Waveform {
             2 => Self::Square,
             3 => Self::Saw,
             4 => Self::Triangle,
+            5 => Self::Wavetable,
+            6 => Self::WhiteNoise,
+            7 => Self::PinkNoise,
+            8 => Self::BrownNoise,
             _ => panic!("Invalid waveform integer"),
         }
     }
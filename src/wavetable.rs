@@ -0,0 +1,270 @@
+/*
+ * Copyright (C) 2024 Marcus L. Hanestad  <marlhan@proton.me>
+ *
+ * VirtSynth is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * VirtSynth is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with VirtSynth .  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Single-cycle wavetables, mip-mapped per octave.
+
+use std::{f32::consts::TAU, fs::File, io::BufReader, mem, path::Path, sync::Arc};
+
+use crate::wav;
+
+/// Frame size of wavetable files unless told otherwise.
+pub const FRAME_SIZE: usize = 2048;
+
+/// Frames beyond this are ignored when loading.
+pub const MAX_FRAMES: usize = 256;
+
+/// A set of single-cycle frames. Playback interpolates between neighbouring
+/// frames by position, and picks the mip level that has no partials above
+/// Nyquist for the note played.
+pub struct Wavetable {
+    frame_size: usize,
+    frames: usize,
+    /// All frames for each level, back to back. Level 0 has every harmonic
+    /// and each level after it has half as many.
+    levels: Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+    /// Builds a table from consecutive frames of `frame_size` samples. A
+    /// trailing partial frame is dropped.
+    pub fn new(samples: &[f32], frame_size: usize) -> Result<Self, String> {
+        if !frame_size.is_power_of_two() || frame_size < 4 {
+            return Err(format!("frame size {frame_size} is not a power of two"));
+        }
+        let frames = (samples.len() / frame_size).min(MAX_FRAMES);
+        if frames == 0 {
+            return Err(format!(
+                "{} samples is less than one frame of {frame_size}",
+                samples.len()
+            ));
+        }
+
+        let level_count = (frame_size / 2).trailing_zeros() as usize + 1;
+        let mut levels = vec![Vec::with_capacity(frames * frame_size); level_count];
+        let mut re = vec![0.0; frame_size];
+        let mut im = vec![0.0; frame_size];
+        for frame in samples.chunks_exact(frame_size).take(frames) {
+            let mut spectrum_re = frame.to_vec();
+            let mut spectrum_im = vec![0.0; frame_size];
+            fft(&mut spectrum_re, &mut spectrum_im, false);
+            // No DC, it would only add an offset to the voice.
+            spectrum_re[0] = 0.0;
+            spectrum_im[0] = 0.0;
+
+            for (level, samples) in levels.iter_mut().enumerate() {
+                let harmonics = (frame_size / 2) >> level;
+                for bin in 0..frame_size {
+                    let keep = bin.min(frame_size - bin) <= harmonics;
+                    re[bin] = if keep { spectrum_re[bin] } else { 0.0 };
+                    im[bin] = if keep { spectrum_im[bin] } else { 0.0 };
+                }
+                fft(&mut re, &mut im, true);
+                samples.extend_from_slice(&re);
+            }
+        }
+
+        let peak = levels[0].iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        if peak > 0.0 {
+            for sample in levels.iter_mut().flatten() {
+                *sample /= peak;
+            }
+        }
+
+        Ok(Self {
+            frame_size,
+            frames,
+            levels,
+        })
+    }
+
+    /// Loads the first channel of a WAV file as frames of `frame_size`
+    /// samples.
+    pub fn load(path: &Path, frame_size: usize) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+        let wav = wav::read(BufReader::new(file)).map_err(|err| err.to_string())?;
+        let samples: Vec<f32> = wav
+            .samples
+            .iter()
+            .step_by(wav.channels as usize)
+            .copied()
+            .collect();
+        Self::new(&samples, frame_size)
+    }
+
+    /// Sine, triangle, saw and square, in that order.
+    pub fn basic() -> Self {
+        let mut samples = Vec::with_capacity(4 * FRAME_SIZE);
+        let phases = (0..FRAME_SIZE).map(|i| i as f32 / FRAME_SIZE as f32);
+        samples.extend(phases.clone().map(|phase| (phase * TAU).sin()));
+        samples.extend(
+            phases
+                .clone()
+                .map(|phase| 1.0 - 4.0 * ((phase + 0.25) % 1.0 - 0.5).abs()),
+        );
+        samples.extend(
+            phases
+                .clone()
+                .map(|phase| 2.0 * ((phase + 0.5) % 1.0) - 1.0),
+        );
+        samples.extend(phases.map(|phase| if phase < 0.5 { 1.0 } else { -1.0 }));
+        Self::new(&samples, FRAME_SIZE).expect("basic wavetable is valid")
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Output at `phase` of the frame at `position` (`0.0..=1.0` across the
+    /// table), for a note advancing `dt` cycles per sample.
+    #[inline(always)]
+    pub fn sample(&self, position: f32, phase: f32, dt: f32) -> f32 {
+        // Level `n` has `frame_size / 2 >> n` harmonics, which must stay
+        // below Nyquist at `0.5 / dt`.
+        let level = (self.frame_size as f32 * dt).log2().ceil().max(0.0) as usize;
+        let samples = &self.levels[level.min(self.levels.len() - 1)];
+
+        let position = position.clamp(0.0, 1.0) * (self.frames - 1) as f32;
+        let frame = (position as usize).min(self.frames - 1);
+        let frac = position - frame as f32;
+
+        let a = self.read(samples, frame, phase);
+        if frac > 0.0 {
+            a + (self.read(samples, frame + 1, phase) - a) * frac
+        } else {
+            a
+        }
+    }
+
+    #[inline(always)]
+    fn read(&self, samples: &[f32], frame: usize, phase: f32) -> f32 {
+        let frame = &samples[frame * self.frame_size..(frame + 1) * self.frame_size];
+        let index = phase.rem_euclid(1.0) * self.frame_size as f32;
+        let i = index as usize % self.frame_size;
+        let frac = index - index.floor();
+        let a = frame[i];
+        let b = frame[(i + 1) % self.frame_size];
+        a + (b - a) * frac
+    }
+}
+
+impl Default for Wavetable {
+    fn default() -> Self {
+        Self::basic()
+    }
+}
+
+/// The table an oscillator plays, shared between the GUI and the audio
+/// thread. The audio thread hands back the table it stops playing instead of
+/// dropping it, so freeing it happens on the thread that loads the next one.
+pub struct WavetableSlot {
+    table: Arc<Wavetable>,
+    retired: Option<Arc<Wavetable>>,
+}
+
+impl WavetableSlot {
+    pub fn new(table: Wavetable) -> Self {
+        Self {
+            table: Arc::new(table),
+            retired: None,
+        }
+    }
+
+    pub fn table(&self) -> &Arc<Wavetable> {
+        &self.table
+    }
+
+    /// Replaces the table and frees the one the audio thread last retired.
+    pub fn set(&mut self, table: Wavetable) {
+        self.table = Arc::new(table);
+        self.retired = None;
+    }
+
+    /// Swaps `playing` for the current table if they differ, keeping the old
+    /// one here. Waits while an earlier table has not been freed yet.
+    #[inline(always)]
+    pub(crate) fn swap(&mut self, playing: &mut Arc<Wavetable>) {
+        if !Arc::ptr_eq(&self.table, playing) && self.retired.is_none() {
+            self.retired = Some(mem::replace(playing, Arc::clone(&self.table)));
+        }
+    }
+}
+
+/// In-place radix-2 FFT, `re.len()` must be a power of two. The inverse is
+/// scaled by `1 / n`.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = if inverse { TAU } else { -TAU } / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        for (re, im) in re.iter_mut().zip(im.iter_mut()) {
+            *re /= n as f32;
+            *im /= n as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_keeps_the_retired_table() {
+        let mut slot = WavetableSlot::new(Wavetable::basic());
+        let mut playing = Arc::clone(slot.table());
+        let old = Arc::downgrade(&playing);
+
+        slot.set(Wavetable::basic());
+        slot.swap(&mut playing);
+        assert!(Arc::ptr_eq(&playing, slot.table()));
+        assert!(old.upgrade().is_some());
+
+        slot.set(Wavetable::basic());
+        assert!(old.upgrade().is_none());
+    }
+}